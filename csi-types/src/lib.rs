@@ -1,4 +1,8 @@
 pub mod ser;
+pub mod npy;
+pub mod mat;
+pub mod stats;
+pub mod auth;
 use ser::{SerCSI, ComplexDef};
 
 use std::fs;
@@ -36,30 +40,44 @@ pub fn bit_convert(data: isize, maxbit: isize) -> isize {
     return d;
 }
 
+/// Hardware timestamp from the first 8 bytes of a record, in the byte order
+/// of the host that wrote it
+pub fn decode_tstamp(buf: &[u8], big_endian: bool) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
 
+    if big_endian {
+        u64::from_be_bytes(bytes)
+    } else {
+        u64::from_le_bytes(bytes)
+    }
+}
+
+
+#[allow(non_snake_case)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CSIStruct {
-    tstamp: u64,         /* h/w assigned time stamp */
+    pub tstamp: u64,         /* h/w assigned time stamp */
     
-    channel: u16,        /* wireless channel (represented in Hz)*/
-    chanBW: u8,         /* channel bandwidth (0->20MHz,1->40MHz)*/
+    pub channel: u16,        /* wireless channel (represented in Hz)*/
+    pub chanBW: u8,         /* channel bandwidth (0->20MHz,1->40MHz)*/
 
-    rate: u8,           /* transmission rate*/
-    nr: u8,             /* number of receiving antenna*/
-    nc: u8,             /* number of transmitting antenna*/
-    num_tones: u8,      /* number of tones (subcarriers) */
-    noise: u8,          /* noise floor (to be updated)*/
+    pub rate: u8,           /* transmission rate*/
+    pub nr: u8,             /* number of receiving antenna*/
+    pub nc: u8,             /* number of transmitting antenna*/
+    pub num_tones: u8,      /* number of tones (subcarriers) */
+    pub noise: u8,          /* noise floor (to be updated)*/
 
-    phyerr: u8,          /* phy error code (set to 0 if correct)*/
+    pub phyerr: u8,          /* phy error code (set to 0 if correct)*/
 
-    rssi: u8,         /*  rx frame RSSI */
-    rssi_0: u8,       /*  rx frame RSSI [ctl, chain 0] */
-    rssi_1: u8,       /*  rx frame RSSI [ctl, chain 1] */
-    rssi_2: u8,       /*  rx frame RSSI [ctl, chain 2] */
+    pub rssi: u8,         /*  rx frame RSSI */
+    pub rssi_0: u8,       /*  rx frame RSSI [ctl, chain 0] */
+    pub rssi_1: u8,       /*  rx frame RSSI [ctl, chain 1] */
+    pub rssi_2: u8,       /*  rx frame RSSI [ctl, chain 2] */
 
     pub payload_len: u16,  /*  payload length (bytes) */
-    csi_len: u16,      /*  csi data length (bytes) */
-    buf_len: u16,      /*  data length in buffer */
+    pub csi_len: u16,      /*  csi data length (bytes) */
+    pub buf_len: u16,      /*  data length in buffer */
 }
 
 impl CSIStruct {
@@ -115,9 +133,12 @@ impl CSI {
             ).collect()
         ).collect();
         
+        let payload_len = (self.csi_status.payload_len as usize).min(self.data_buf.len());
+
         SerCSI {
             csi_matrix: mm,
             csi_status: self.csi_status.clone(),
+            payload: self.data_buf[..payload_len].to_vec(),
         }
     }
 
@@ -239,15 +260,7 @@ impl CSI {
     pub fn record_status(&mut self, cnt: usize) {
         if is_big_endian() {
 
-            self.csi_status.tstamp =   
-                  (((self.buf[0] as u64) << 56) & 0x00000000000000ff)
-                | (((self.buf[1] as u64) << 48) & 0x000000000000ff00)
-                | (((self.buf[2] as u64) << 40) & 0x0000000000ff0000)
-                | (((self.buf[3] as u64) << 32) & 0x00000000ff000000)
-                | (((self.buf[4] as u64) << 24) & 0x000000ff00000000)
-                | (((self.buf[5] as u64) << 16) & 0x0000ff0000000000)
-                | (((self.buf[6] as u64) <<  8) & 0x00ff000000000000)
-                | (((self.buf[7] as u64)      ) & 0xff00000000000000);
+            self.csi_status.tstamp = decode_tstamp(&self.buf, true);
             
             self.csi_status.csi_len =
                  (((self.buf[8] as u16) << 8) & 0xff00)
@@ -267,15 +280,7 @@ impl CSI {
 
         } else {
 
-            self.csi_status.tstamp =   
-                  (((self.buf[7] as u64) << 56) & 0x00000000000000ff)
-                | (((self.buf[6] as u64) << 48) & 0x000000000000ff00)
-                | (((self.buf[5] as u64) << 40) & 0x0000000000ff0000)
-                | (((self.buf[4] as u64) << 32) & 0x00000000ff000000)
-                | (((self.buf[3] as u64) << 24) & 0x000000ff00000000)
-                | (((self.buf[2] as u64) << 16) & 0x0000ff0000000000)
-                | (((self.buf[1] as u64) <<  8) & 0x00ff000000000000)
-                | (((self.buf[0] as u64)      ) & 0xff00000000000000);
+            self.csi_status.tstamp = decode_tstamp(&self.buf, false);
             
             self.csi_status.csi_len =
                  (((self.buf[9] as u16) << 8) & 0xff00)
@@ -339,4 +344,11 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn tstamp_byte_order() {
+        let buf = [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0xff];
+        assert_eq!(super::decode_tstamp(&buf, false), 0x1122334455667788);
+        assert_eq!(super::decode_tstamp(&buf, true), 0x8877665544332211);
+    }
 }
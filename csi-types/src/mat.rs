@@ -0,0 +1,350 @@
+//! Minimal MATLAB Level-5 `.mat` writer
+//!
+//! Supports the subset needed to reproduce the output of the Atheros CSI
+//! Tool's `read_log_file.m`: double (optionally complex) arrays, cell arrays
+//! and struct arrays, all uncompressed, plus character arrays for labels.
+
+use std::io::{self, Write};
+
+use crate::ser::ComplexDef;
+use crate::CSIStruct;
+
+const MI_INT8: u32 = 1;
+const MI_UINT16: u32 = 4;
+const MI_INT32: u32 = 5;
+const MI_UINT32: u32 = 6;
+const MI_DOUBLE: u32 = 9;
+const MI_MATRIX: u32 = 14;
+
+const MX_CELL_CLASS: u32 = 1;
+const MX_STRUCT_CLASS: u32 = 2;
+const MX_CHAR_CLASS: u32 = 4;
+const MX_DOUBLE_CLASS: u32 = 6;
+
+const COMPLEX_FLAG: u32 = 0x0800;
+
+/// Space reserved per struct field name, including the terminating NUL.
+/// 32 is what MATLAB versions without long field names can read.
+const FIELD_NAME_LEN: usize = 32;
+
+/// A MATLAB array; data is stored in column-major order
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatValue {
+    Double {
+        dims: Vec<usize>,
+        re: Vec<f64>,
+        im: Option<Vec<f64>>,
+    },
+    Cell {
+        dims: Vec<usize>,
+        items: Vec<MatValue>,
+    },
+    Struct {
+        dims: Vec<usize>,
+        fields: Vec<String>,
+        /// One entry per struct element, values in the order of `fields`
+        items: Vec<Vec<MatValue>>,
+    },
+    /// A `1 x n` character array
+    Char(String),
+}
+
+impl MatValue {
+    pub fn scalar(v: f64) -> Self {
+        MatValue::Double {
+            dims: vec![1, 1],
+            re: vec![v],
+            im: None,
+        }
+    }
+
+    /// A column vector, as returned by `fread`
+    pub fn column(values: Vec<f64>) -> Self {
+        MatValue::Double {
+            dims: vec![values.len(), 1],
+            re: values,
+            im: None,
+        }
+    }
+
+    /// A `n x 1` cell array
+    pub fn cell_column(items: Vec<MatValue>) -> Self {
+        MatValue::Cell {
+            dims: vec![items.len(), 1],
+            items,
+        }
+    }
+
+    /// A 1x1 struct
+    pub fn record(fields: Vec<(&str, MatValue)>) -> Self {
+        let (names, values) = fields.into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .unzip();
+
+        MatValue::Struct {
+            dims: vec![1, 1],
+            fields: names,
+            items: vec![values],
+        }
+    }
+}
+
+/// Build the struct `read_log_file.m` returns for one frame.
+///
+/// `csi` is indexed `[rx][tx][tone]` and only its first `nr x nc x
+/// num_tones` values (as reported by `status`) are used. Every field is a
+/// double, like `fread` produces.
+pub fn frame_struct(status: &CSIStruct, csi: &[Vec<Vec<ComplexDef<isize>>>], payload: &[u8]) -> MatValue {
+    let nr = (status.nr as usize).min(csi.len());
+    let nc = (status.nc as usize).min(csi.first().map_or(0, Vec::len));
+    let tones = (status.num_tones as usize)
+        .min(csi.first().and_then(|r| r.first()).map_or(0, Vec::len));
+
+    let mut re = Vec::with_capacity(nr * nc * tones);
+    let mut im = Vec::with_capacity(nr * nc * tones);
+    // column-major: rx varies fastest
+    for k in 0..tones {
+        for tx in 0..nc {
+            for row in &csi[..nr] {
+                re.push(row[tx][k].re as f64);
+                im.push(row[tx][k].im as f64);
+            }
+        }
+    }
+
+    let s = MatValue::scalar;
+    MatValue::record(vec![
+        ("timestamp", s(status.tstamp as f64)),
+        ("csi_len", s(status.csi_len as f64)),
+        ("channel", s(status.channel as f64)),
+        ("err_info", s(status.phyerr as f64)),
+        ("noise_floor", s(status.noise as f64)),
+        ("Rate", s(status.rate as f64)),
+        ("bandWidth", s(status.chanBW as f64)),
+        ("num_tones", s(status.num_tones as f64)),
+        ("nr", s(status.nr as f64)),
+        ("nc", s(status.nc as f64)),
+        ("rssi", s(status.rssi as f64)),
+        ("rssi1", s(status.rssi_0 as f64)),
+        ("rssi2", s(status.rssi_1 as f64)),
+        ("rssi3", s(status.rssi_2 as f64)),
+        ("payload_len", s(status.payload_len as f64)),
+        ("csi", MatValue::Double { dims: vec![nr, nc, tones], re, im: Some(im) }),
+        ("payload", MatValue::column(payload.iter().map(|&b| b as f64).collect())),
+    ])
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "mat elements over 4 GiB are not supported")
+}
+
+fn to_u32(v: usize) -> io::Result<u32> {
+    if v <= u32::MAX as usize {
+        Ok(v as u32)
+    } else {
+        Err(too_big())
+    }
+}
+
+/// Append a data element, padded to 8 bytes
+fn element(out: &mut Vec<u8>, ty: u32, data: &[u8]) -> io::Result<()> {
+    if !data.is_empty() && data.len() <= 4 {
+        // small data element format: type and size share the tag
+        out.extend_from_slice(&(ty | (data.len() as u32) << 16).to_le_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0; 4][..4 - data.len()]);
+        return Ok(());
+    }
+
+    out.extend_from_slice(&ty.to_le_bytes());
+    out.extend_from_slice(&to_u32(data.len())?.to_le_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(&[0; 8][..(8 - data.len() % 8) % 8]);
+
+    Ok(())
+}
+
+fn dims_bytes(dims: &[usize]) -> io::Result<Vec<u8>> {
+    let mut res = vec![];
+    for &d in dims {
+        res.extend_from_slice(&(to_u32(d)? as i32).to_le_bytes());
+    }
+
+    Ok(res)
+}
+
+fn doubles(values: &[f64]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+/// Encode a `miMATRIX` element
+fn matrix(out: &mut Vec<u8>, name: &str, value: &MatValue) -> io::Result<()> {
+    let mut body = vec![];
+
+    let char_dims;
+    let (class, dims) = match value {
+        MatValue::Double { dims, im, .. } => {
+            let flags = if im.is_some() { COMPLEX_FLAG } else { 0 };
+            (MX_DOUBLE_CLASS | flags, dims)
+        }
+        MatValue::Cell { dims, .. } => (MX_CELL_CLASS, dims),
+        MatValue::Struct { dims, .. } => (MX_STRUCT_CLASS, dims),
+        MatValue::Char(s) => {
+            char_dims = vec![1, s.encode_utf16().count()];
+            (MX_CHAR_CLASS, &char_dims)
+        }
+    };
+
+    let mut flags = class.to_le_bytes().to_vec();
+    flags.extend_from_slice(&0u32.to_le_bytes());
+    element(&mut body, MI_UINT32, &flags)?;
+    element(&mut body, MI_INT32, &dims_bytes(dims)?)?;
+    element(&mut body, MI_INT8, name.as_bytes())?;
+
+    match value {
+        MatValue::Double { re, im, .. } => {
+            element(&mut body, MI_DOUBLE, &doubles(re))?;
+            if let Some(im) = im {
+                element(&mut body, MI_DOUBLE, &doubles(im))?;
+            }
+        }
+        MatValue::Cell { items, .. } => {
+            for item in items {
+                matrix(&mut body, "", item)?;
+            }
+        }
+        MatValue::Struct { fields, items, .. } => {
+            element(&mut body, MI_INT32, &(FIELD_NAME_LEN as i32).to_le_bytes())?;
+
+            let mut names = vec![0u8; fields.len() * FIELD_NAME_LEN];
+            for (i, f) in fields.iter().enumerate() {
+                if f.len() >= FIELD_NAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("field name too long: {}", f),
+                    ));
+                }
+                names[i * FIELD_NAME_LEN..i * FIELD_NAME_LEN + f.len()].copy_from_slice(f.as_bytes());
+            }
+            element(&mut body, MI_INT8, &names)?;
+
+            for item in items {
+                for v in item {
+                    matrix(&mut body, "", v)?;
+                }
+            }
+        }
+        MatValue::Char(s) => {
+            let data: Vec<u8> = s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect();
+            element(&mut body, MI_UINT16, &data)?;
+        }
+    }
+
+    out.extend_from_slice(&MI_MATRIX.to_le_bytes());
+    out.extend_from_slice(&to_u32(body.len())?.to_le_bytes());
+    out.extend_from_slice(&body);
+
+    Ok(())
+}
+
+/// Write named variables as a Level-5 `.mat` file
+pub fn write_mat<W: Write>(mut w: W, vars: &[(&str, &MatValue)]) -> io::Result<()> {
+    let mut header = b"MATLAB 5.0 MAT-file, Platform: csi-tools".to_vec();
+    header.resize(116, b' ');
+    header.extend_from_slice(&[0; 8]); // no subsystem data
+    header.extend_from_slice(&0x0100u16.to_le_bytes()); // version
+    header.extend_from_slice(b"IM"); // little endian
+    w.write_all(&header)?;
+
+    for (name, value) in vars {
+        let mut out = vec![];
+        matrix(&mut out, name, value)?;
+        w.write_all(&out)?;
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+    }
+
+    #[test]
+    fn header() {
+        let mut out = vec![];
+        write_mat(&mut out, &[]).unwrap();
+
+        assert_eq!(out.len(), 128);
+        assert!(out.starts_with(b"MATLAB 5.0 MAT-file"));
+        assert_eq!(&out[124..], &[0x00, 0x01, b'I', b'M']);
+    }
+
+    #[test]
+    fn scalar_layout() {
+        let mut out = vec![];
+        matrix(&mut out, "a", &MatValue::scalar(1.5)).unwrap();
+
+        assert_eq!(u32_at(&out, 0), MI_MATRIX);
+        assert_eq!(u32_at(&out, 4) as usize, out.len() - 8);
+        // array flags: double class, not complex
+        assert_eq!(u32_at(&out, 8), MI_UINT32);
+        assert_eq!(u32_at(&out, 16), MX_DOUBLE_CLASS);
+        // dimensions 1x1
+        assert_eq!(u32_at(&out, 24), MI_INT32);
+        assert_eq!(u32_at(&out, 32), 1);
+        assert_eq!(u32_at(&out, 36), 1);
+        // name in the small element format
+        assert_eq!(u32_at(&out, 40), MI_INT8 | 1 << 16);
+        assert_eq!(out[44], b'a');
+        // value
+        assert_eq!(u32_at(&out, 48), MI_DOUBLE);
+        assert_eq!(&out[56..64], &1.5f64.to_le_bytes());
+        assert_eq!(out.len(), 64);
+    }
+
+    #[test]
+    fn char_layout() {
+        let mut out = vec![];
+        matrix(&mut out, "s", &MatValue::Char("wälk".to_string())).unwrap();
+
+        assert_eq!(u32_at(&out, 16), MX_CHAR_CLASS);
+        // dimensions 1x4
+        assert_eq!(u32_at(&out, 32), 1);
+        assert_eq!(u32_at(&out, 36), 4);
+        // utf-16 code units
+        assert_eq!(u32_at(&out, 48), MI_UINT16);
+        assert_eq!(u32_at(&out, 52), 8);
+        assert_eq!(&out[56..64], &[b'w', 0, 0xe4, 0, b'l', 0, b'k', 0]);
+        assert_eq!(out.len(), 64);
+    }
+
+    #[test]
+    fn frame_struct_fields() {
+        let mut status = CSIStruct::new();
+        status.nr = 1;
+        status.nc = 2;
+        status.num_tones = 3;
+        let csi = vec![vec![vec![ComplexDef { re: 1, im: -1 }; 114]; 3]; 3];
+
+        match frame_struct(&status, &csi, &[1, 2]) {
+            MatValue::Struct { fields, items, .. } => {
+                assert_eq!(fields.len(), 17);
+                assert_eq!(fields[15], "csi");
+                match &items[0][15] {
+                    MatValue::Double { dims, re, im } => {
+                        assert_eq!(dims, &vec![1, 2, 3]);
+                        assert_eq!(re.len(), 6);
+                        assert_eq!(im.as_ref().unwrap()[0], -1.0);
+                    }
+                    v => panic!("unexpected csi value {:?}", v),
+                }
+                assert_eq!(items[0][16], MatValue::column(vec![1.0, 2.0]));
+            }
+            v => panic!("unexpected value {:?}", v),
+        }
+    }
+}
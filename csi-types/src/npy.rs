@@ -0,0 +1,291 @@
+//! Minimal NumPy `.npy` and `.npz` writers
+//!
+//! Arrays are written as version 1.0 `.npy` files in little-endian order;
+//! `.npz` archives are uncompressed (stored) zip files, which `numpy.load`
+//! reads directly.
+
+use std::io::{self, Write};
+
+use crate::ser::ComplexDef;
+
+/// A value that can be stored in a `.npy` array
+pub trait Element {
+    /// NumPy dtype descriptor, e.g. `<f4`
+    const DESCR: &'static str;
+
+    fn write_le(&self, out: &mut Vec<u8>);
+}
+
+macro_rules! element {
+    ($t:ty, $descr:expr) => {
+        impl Element for $t {
+            const DESCR: &'static str = $descr;
+
+            fn write_le(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    };
+}
+
+element!(u8, "|u1");
+element!(u16, "<u2");
+element!(u32, "<u4");
+element!(u64, "<u8");
+element!(i16, "<i2");
+element!(i32, "<i4");
+element!(i64, "<i8");
+element!(f32, "<f4");
+element!(f64, "<f8");
+
+impl Element for ComplexDef<f32> {
+    const DESCR: &'static str = "<c8";
+
+    fn write_le(&self, out: &mut Vec<u8>) {
+        self.re.write_le(out);
+        self.im.write_le(out);
+    }
+}
+
+impl Element for ComplexDef<f64> {
+    const DESCR: &'static str = "<c16";
+
+    fn write_le(&self, out: &mut Vec<u8>) {
+        self.re.write_le(out);
+        self.im.write_le(out);
+    }
+}
+
+/// Microseconds since the Unix epoch, stored as `datetime64[us]`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime64Us(pub i64);
+
+impl Element for DateTime64Us {
+    const DESCR: &'static str = "<M8[us]";
+
+    fn write_le(&self, out: &mut Vec<u8>) {
+        self.0.write_le(out);
+    }
+}
+
+/// Encode an array as a `.npy` file
+pub fn to_npy<T: Element>(shape: &[usize], data: &[T]) -> io::Result<Vec<u8>> {
+    let expected: usize = shape.iter().product();
+    if expected != data.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("shape {:?} needs {} elements, got {}", shape, expected, data.len()),
+        ));
+    }
+
+    let shape = match shape.len() {
+        1 => format!("({},)", shape[0]),
+        _ => format!(
+            "({})",
+            shape.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        T::DESCR, shape
+    );
+
+    // magic (6) + version (2) + header length (2) + header + '\n',
+    // padded to a multiple of 64 bytes
+    let unpadded = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    if header.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "npy header too long"));
+    }
+
+    let mut out = Vec::with_capacity(10 + header.len() + data.len() * 8);
+    out.extend_from_slice(b"\x93NUMPY\x01\x00");
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for v in data {
+        v.write_le(&mut out);
+    }
+
+    Ok(out)
+}
+
+/// CRC-32 (IEEE) as used by zip
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes arrays into an uncompressed `.npz` archive.
+///
+/// `finish` must be called to write the zip central directory.
+pub struct NpzWriter<W: Write> {
+    out: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> NpzWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    /// Add an array, loaded by `numpy.load` as `npz[name]`
+    pub fn add_array<T: Element>(&mut self, name: &str, shape: &[usize], data: &[T]) -> io::Result<()> {
+        let npy = to_npy(shape, data)?;
+        self.add_file(&format!("{}.npy", name), &npy)
+    }
+
+    /// Add an arbitrary file, e.g. JSON metadata
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let too_big = || io::Error::new(io::ErrorKind::InvalidInput, "npz archives over 4 GiB are not supported");
+        let size = to_u32(data.len() as u64).ok_or_else(too_big)?;
+        let offset = to_u32(self.offset).ok_or_else(too_big)?;
+        let crc = crc32(data);
+
+        let mut header = vec![];
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes()); // local file header
+        header.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        header.extend_from_slice(&0u16.to_le_bytes()); // flags
+        header.extend_from_slice(&0u16.to_le_bytes()); // method: stored
+        header.extend_from_slice(&0u16.to_le_bytes()); // mod time
+        header.extend_from_slice(&0x21u16.to_le_bytes()); // mod date: 1980-01-01
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes()); // compressed
+        header.extend_from_slice(&size.to_le_bytes()); // uncompressed
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra length
+        header.extend_from_slice(name.as_bytes());
+
+        self.out.write_all(&header)?;
+        self.out.write_all(data)?;
+        self.offset += (header.len() + data.len()) as u64;
+
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+
+        Ok(())
+    }
+
+    /// Write the central directory and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let start = self.offset;
+        let mut dir = vec![];
+
+        for e in &self.entries {
+            dir.extend_from_slice(&0x0201_4b50u32.to_le_bytes()); // central directory header
+            dir.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            dir.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            dir.extend_from_slice(&0u16.to_le_bytes()); // flags
+            dir.extend_from_slice(&0u16.to_le_bytes()); // method: stored
+            dir.extend_from_slice(&0u16.to_le_bytes()); // mod time
+            dir.extend_from_slice(&0x21u16.to_le_bytes()); // mod date
+            dir.extend_from_slice(&e.crc.to_le_bytes());
+            dir.extend_from_slice(&e.size.to_le_bytes());
+            dir.extend_from_slice(&e.size.to_le_bytes());
+            dir.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+            dir.extend_from_slice(&0u16.to_le_bytes()); // extra length
+            dir.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            dir.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            dir.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            dir.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+            dir.extend_from_slice(&e.offset.to_le_bytes());
+            dir.extend_from_slice(e.name.as_bytes());
+        }
+
+        let too_big = || io::Error::new(io::ErrorKind::InvalidInput, "npz archives over 4 GiB are not supported");
+        let count = self.entries.len() as u16;
+        let dir_size = to_u32(dir.len() as u64).ok_or_else(too_big)?;
+        let dir_offset = to_u32(start).ok_or_else(too_big)?;
+
+        dir.extend_from_slice(&0x0605_4b50u32.to_le_bytes()); // end of central directory
+        dir.extend_from_slice(&0u16.to_le_bytes()); // disk number
+        dir.extend_from_slice(&0u16.to_le_bytes()); // disk with the directory
+        dir.extend_from_slice(&count.to_le_bytes());
+        dir.extend_from_slice(&count.to_le_bytes());
+        dir.extend_from_slice(&dir_size.to_le_bytes());
+        dir.extend_from_slice(&dir_offset.to_le_bytes());
+        dir.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        self.out.write_all(&dir)?;
+        self.out.flush()?;
+
+        Ok(self.out)
+    }
+}
+
+fn to_u32(v: u64) -> Option<u32> {
+    if v <= u32::MAX as u64 {
+        Some(v as u32)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let npy = to_npy(&[2, 3], &[0f32; 6]).unwrap();
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        assert_eq!((10 + header_len) % 64, 0);
+        assert_eq!(npy[10 + header_len - 1], b'\n');
+        assert_eq!(npy.len(), 10 + header_len + 6 * 4);
+
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+    }
+
+    #[test]
+    fn npy_rejects_wrong_shape() {
+        assert!(to_npy(&[4], &[1u8, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn npz_layout() {
+        let mut w = NpzWriter::new(vec![]);
+        w.add_array("rssi", &[3], &[1u8, 2, 3]).unwrap();
+        w.add_file("metadata.json", b"{}").unwrap();
+        let zip = w.finish().unwrap();
+
+        // the archive starts with a local header and ends with the
+        // end-of-central-directory record listing both entries
+        assert_eq!(&zip[..4], &[0x50, 0x4b, 0x03, 0x04]);
+        let eocd = &zip[zip.len() - 22..];
+        assert_eq!(&eocd[..4], &[0x50, 0x4b, 0x05, 0x06]);
+        assert_eq!(u16::from_le_bytes([eocd[10], eocd[11]]), 2);
+    }
+}
@@ -2,20 +2,133 @@ use serde::{Deserialize, Serialize};
 
 use crate::CSIStruct;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ComplexDef<T> {
     pub re: T,
     pub im: T,
 }
 
+/// Range of the 10-bit two's complement values of a subcarrier
+pub const CSI_VALUE_MIN: isize = -512;
+pub const CSI_VALUE_MAX: isize = 511;
+
 /// 'Absolute' value of a subcarrier
 pub fn abs(c: ComplexDef<isize>) -> f64 {
     ((c.re.pow(2) +  c.im.pow(2)) as f64).sqrt()
 }
 
+/// Phase of a subcarrier, radians
+pub fn phase(c: ComplexDef<isize>) -> f64 {
+    (c.im as f64).atan2(c.re as f64)
+}
+
 /// Serialization type
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SerCSI {
     pub csi_matrix: Vec<Vec<Vec<ComplexDef<isize>>>>,
     pub csi_status: CSIStruct,
+    /// Received 802.11 frame, `csi_status.payload_len` bytes
+    #[serde(default)]
+    pub payload: Vec<u8>,
+}
+
+/// `SerCSI` as sent by receivers built before `payload` was added. Bincode
+/// is not self-describing, so the server decodes it on its own.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SerCSIv1 {
+    pub csi_matrix: Vec<Vec<Vec<ComplexDef<isize>>>>,
+    pub csi_status: CSIStruct,
+}
+
+impl From<SerCSIv1> for SerCSI {
+    fn from(v1: SerCSIv1) -> Self {
+        Self {
+            csi_matrix: v1.csi_matrix,
+            csi_status: v1.csi_status,
+            payload: vec![],
+        }
+    }
+}
+
+impl SerCSI {
+    /// Check a matrix received from the network before it is used.
+    ///
+    /// The matrix must be rectangular and hold 10-bit values. It may be
+    /// larger than `nr`, `nc` and `num_tones` of the header (the receiver
+    /// allocates it for the largest configuration), but not smaller.
+    pub fn validate(&self) -> Result<(), String> {
+        let nr = self.csi_matrix.len();
+        let nc = self.csi_matrix.first().map_or(0, Vec::len);
+        let tones = self.csi_matrix.first()
+            .and_then(|r| r.first())
+            .map_or(0, Vec::len);
+
+        for (rx, row) in self.csi_matrix.iter().enumerate() {
+            if row.len() != nc {
+                return Err(format!("csi_matrix[{}] has {} tx antennas, expected {}", rx, row.len(), nc));
+            }
+            for (tx, values) in row.iter().enumerate() {
+                if values.len() != tones {
+                    return Err(format!("csi_matrix[{}][{}] has {} tones, expected {}", rx, tx, values.len(), tones));
+                }
+                let range = CSI_VALUE_MIN..=CSI_VALUE_MAX;
+                if let Some(c) = values.iter().find(|c| !range.contains(&c.re) || !range.contains(&c.im)) {
+                    return Err(format!("csi_matrix[{}][{}] holds {}{:+}i, outside of 10 bits", rx, tx, c.re, c.im));
+                }
+            }
+        }
+
+        let st = &self.csi_status;
+        for (name, reported, present) in [("nr", st.nr, nr), ("nc", st.nc, nc), ("num_tones", st.num_tones, tones)].iter() {
+            if *reported as usize > *present {
+                return Err(format!("{} is {} but csi_matrix only has {}", name, reported, present));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ser(nr: usize, nc: usize, tones: usize) -> SerCSI {
+        let mut csi_status = CSIStruct::new();
+        csi_status.nr = nr as u8;
+        csi_status.nc = nc as u8;
+        csi_status.num_tones = tones as u8;
+
+        SerCSI {
+            csi_matrix: vec![vec![vec![ComplexDef { re: 511, im: -512 }; tones]; nc]; nr],
+            csi_status,
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(ser(2, 1, 56).validate(), Ok(()));
+
+        // allocated for more than the header reports
+        let mut s = ser(3, 3, 114);
+        s.csi_status.nr = 2;
+        assert_eq!(s.validate(), Ok(()));
+
+        let mut s = ser(2, 2, 56);
+        s.csi_matrix[1].pop();
+        assert!(s.validate().unwrap_err().starts_with("csi_matrix[1] has 1"));
+
+        let mut s = ser(2, 2, 56);
+        s.csi_matrix[1][1].truncate(3);
+        assert!(s.validate().unwrap_err().starts_with("csi_matrix[1][1] has 3 tones"));
+
+        let mut s = ser(2, 2, 56);
+        s.csi_matrix[0][1][7].re = 512;
+        assert!(s.validate().unwrap_err().contains("outside of 10 bits"));
+
+        let mut s = ser(2, 2, 56);
+        s.csi_status.num_tones = 114;
+        assert_eq!(s.validate().unwrap_err(), "num_tones is 114 but csi_matrix only has 56");
+    }
 }
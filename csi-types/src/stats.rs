@@ -0,0 +1,293 @@
+//! Timing and loss statistics of a stream of CSI frames
+//!
+//! Intervals are taken from the hardware timestamp (`CSIStruct.tstamp`, the
+//! low 32 bits of the TSF in microseconds on Atheros cards), losses from the
+//! 802.11 sequence numbers of the received frames.
+
+use serde::{Deserialize, Serialize};
+
+/// The hardware timestamp wraps around after this many microseconds
+pub const TSTAMP_WRAP: u64 = 1 << 32;
+/// 802.11 sequence numbers are 12 bits wide
+pub const SEQ_MODULO: u16 = 4096;
+
+/// Sequence number of an 802.11 frame, `None` if the frame is too short to
+/// carry one or is a control frame
+pub fn sequence_number(frame: &[u8]) -> Option<u16> {
+    if frame.len() < 24 {
+        return None;
+    }
+
+    // frame control: type in bits 2-3, 1 is a control frame
+    if (frame[0] >> 2) & 0b11 == 1 {
+        return None;
+    }
+
+    Some(u16::from_le_bytes([frame[22], frame[23]]) >> 4)
+}
+
+/// Fixed-width histogram of inter-arrival intervals
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Width of a bin in microseconds
+    pub bin_us: u64,
+    /// `counts[i]` intervals fell in `[i * bin_us, (i + 1) * bin_us)`
+    pub counts: Vec<u64>,
+    /// Intervals longer than the last bin
+    pub overflow: u64,
+}
+
+impl Histogram {
+    pub fn new(bin_us: u64, bins: usize) -> Self {
+        Self {
+            bin_us: bin_us.max(1),
+            counts: vec![0; bins],
+            overflow: 0,
+        }
+    }
+
+    pub fn add(&mut self, interval_us: u64) {
+        match self.counts.get_mut((interval_us / self.bin_us) as usize) {
+            Some(c) => *c += 1,
+            None => self.overflow += 1,
+        }
+    }
+}
+
+/// Statistics of one device's frames, fed in arrival order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FrameStats {
+    pub frames: u64,
+    last_tstamp: Option<u64>,
+    /// Hardware time covered by the frames, wraparounds unrolled
+    pub elapsed_us: u64,
+    pub wraps: u64,
+    /// Timestamps that went backwards without wrapping, e.g. after a reset
+    /// of the card; the interval is not counted
+    pub resets: u64,
+
+    intervals: u64,
+    mean_us: f64,
+    m2: f64,
+    pub histogram: Histogram,
+
+    last_seq: Option<u16>,
+    /// Frames missing between consecutive sequence numbers
+    pub lost: u64,
+    /// Jumps of the sequence number by more than one
+    pub gaps: u64,
+    /// Frames with the sequence number of the previous one (retries)
+    pub duplicates: u64,
+    /// Frames with an older sequence number than the previous one
+    pub out_of_order: u64,
+}
+
+/// Derived values of `FrameStats`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub frames: u64,
+    pub elapsed_s: f64,
+    /// Frames per second of hardware time
+    pub rate: f64,
+    pub mean_interval_us: f64,
+    /// Standard deviation of the intervals
+    pub jitter_us: f64,
+    pub wraps: u64,
+    pub resets: u64,
+    pub lost: u64,
+    pub gaps: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    /// Share of frames lost according to the sequence numbers
+    pub loss: f64,
+    pub histogram: Histogram,
+}
+
+impl FrameStats {
+    /// Intervals are binned into `bins` bins of `bin_us` microseconds
+    pub fn new(bin_us: u64, bins: usize) -> Self {
+        Self {
+            frames: 0,
+            last_tstamp: None,
+            elapsed_us: 0,
+            wraps: 0,
+            resets: 0,
+            intervals: 0,
+            mean_us: 0.0,
+            m2: 0.0,
+            histogram: Histogram::new(bin_us, bins),
+            last_seq: None,
+            lost: 0,
+            gaps: 0,
+            duplicates: 0,
+            out_of_order: 0,
+        }
+    }
+
+    /// Account a frame: its hardware timestamp and the received 802.11 frame
+    pub fn push(&mut self, tstamp: u64, frame: &[u8]) {
+        self.frames += 1;
+
+        if let Some(last) = self.last_tstamp {
+            let interval = if tstamp >= last {
+                Some(tstamp - last)
+            } else if last - tstamp > TSTAMP_WRAP / 2 && last < TSTAMP_WRAP {
+                self.wraps += 1;
+                Some(tstamp + TSTAMP_WRAP - last)
+            } else {
+                self.resets += 1;
+                None
+            };
+
+            if let Some(interval) = interval {
+                self.add_interval(interval);
+            }
+        }
+        self.last_tstamp = Some(tstamp);
+
+        if let Some(seq) = sequence_number(frame) {
+            if let Some(last) = self.last_seq {
+                let step = (seq + SEQ_MODULO - last) % SEQ_MODULO;
+                if step == 0 {
+                    self.duplicates += 1;
+                } else if step < SEQ_MODULO / 2 {
+                    if step > 1 {
+                        self.gaps += 1;
+                        self.lost += u64::from(step - 1);
+                    }
+                } else {
+                    self.out_of_order += 1;
+                }
+            }
+
+            // keep the newest number, a late frame does not move it back
+            let newer = match self.last_seq {
+                Some(last) => (seq + SEQ_MODULO - last) % SEQ_MODULO < SEQ_MODULO / 2,
+                None => true,
+            };
+            if newer {
+                self.last_seq = Some(seq);
+            }
+        }
+    }
+
+    fn add_interval(&mut self, interval: u64) {
+        self.elapsed_us += interval;
+        self.histogram.add(interval);
+
+        // Welford's online mean and variance
+        self.intervals += 1;
+        let delta = interval as f64 - self.mean_us;
+        self.mean_us += delta / self.intervals as f64;
+        self.m2 += delta * (interval as f64 - self.mean_us);
+    }
+
+    pub fn rate(&self) -> f64 {
+        if self.elapsed_us > 0 {
+            self.intervals as f64 / (self.elapsed_us as f64 / 1e6)
+        } else {
+            0.0
+        }
+    }
+
+    pub fn jitter_us(&self) -> f64 {
+        if self.intervals > 1 {
+            (self.m2 / (self.intervals - 1) as f64).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    pub fn loss(&self) -> f64 {
+        let expected = self.frames - self.duplicates + self.lost;
+        if expected > 0 {
+            self.lost as f64 / expected as f64
+        } else {
+            0.0
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            frames: self.frames,
+            elapsed_s: self.elapsed_us as f64 / 1e6,
+            rate: self.rate(),
+            mean_interval_us: self.mean_us,
+            jitter_us: self.jitter_us(),
+            wraps: self.wraps,
+            resets: self.resets,
+            lost: self.lost,
+            gaps: self.gaps,
+            duplicates: self.duplicates,
+            out_of_order: self.out_of_order,
+            loss: self.loss(),
+            histogram: self.histogram.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A data frame carrying sequence number `seq`
+    fn frame(seq: u16) -> Vec<u8> {
+        let mut f = vec![0u8; 30];
+        f[0] = 0x08;
+        f[22..24].copy_from_slice(&(seq << 4).to_le_bytes());
+        f
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        assert_eq!(sequence_number(&frame(1234)), Some(1234));
+        assert_eq!(sequence_number(&[0u8; 10]), None);
+
+        let mut ack = frame(5);
+        ack[0] = 0xd4;
+        assert_eq!(sequence_number(&ack), None);
+    }
+
+    #[test]
+    fn intervals_and_rate() {
+        let mut st = FrameStats::new(1000, 10);
+        for (i, t) in [0u64, 1000, 2000, 4000, 5000].iter().enumerate() {
+            st.push(*t, &frame(i as u16));
+        }
+
+        assert_eq!(st.elapsed_us, 5000);
+        assert_eq!(st.rate(), 800.0);
+        assert_eq!(st.summary().mean_interval_us, 1250.0);
+        assert_eq!(st.jitter_us(), 500.0);
+        assert_eq!(st.histogram.counts[1], 3);
+        assert_eq!(st.histogram.counts[2], 1);
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut st = FrameStats::new(1000, 10);
+        st.push(TSTAMP_WRAP - 500, &[]);
+        st.push(1500, &[]);
+        // a reset of the card is not a wrap
+        st.push(100, &[]);
+
+        assert_eq!(st.wraps, 1);
+        assert_eq!(st.resets, 1);
+        assert_eq!(st.elapsed_us, 2000);
+    }
+
+    #[test]
+    fn losses() {
+        let mut st = FrameStats::new(1000, 10);
+        for (t, seq) in [4094u16, 4095, 1, 1, 5, 3, 6].iter().enumerate() {
+            st.push(t as u64 * 1000, &frame(*seq));
+        }
+
+        // 0 across the wrap, then 2, 3 and 4, of which 3 came late
+        assert_eq!(st.gaps, 2);
+        assert_eq!(st.lost, 4);
+        assert_eq!(st.duplicates, 1);
+        assert_eq!(st.out_of_order, 1);
+        assert!((st.loss() - 4.0 / 10.0).abs() < 1e-9);
+    }
+}
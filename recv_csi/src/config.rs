@@ -0,0 +1,34 @@
+//! TOML configuration file, `--config`; the keys are the flag names
+//!
+//! ```toml
+//! addr = "https://csi.lab:8899/csi"
+//! device_id = "lab-router-1"
+//! ca = "/etc/recv_csi/ca.pem"
+//! token = "s3cret"
+//! hmac = true
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub addr: Option<String>,
+    pub device_id: Option<String>,
+    pub ca: Option<PathBuf>,
+    pub fingerprint: Option<String>,
+    pub token: Option<String>,
+    pub hmac: Option<bool>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+}
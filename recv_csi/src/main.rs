@@ -1,10 +1,15 @@
 use csi_types as csi;
 
+mod config;
+#[cfg(feature = "tls")]
+mod tls;
+
 const BUF_SIZE: u64 = 4096;
 
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use crossbeam::channel::{bounded, tick, Receiver, select};
 
+use std::io;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -18,30 +23,148 @@ struct Opt {
     #[structopt(parse(from_os_str))]
     output: Option<PathBuf>,
 
+    /// TOML configuration file, flags override its values
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Server URL to post frames to
     #[structopt(long)]
-    addr: String,
+    addr: Option<String>,
+
+    /// Id sent to the server in the X-Device-Id header, hostname if not present
+    #[structopt(long)]
+    device_id: Option<String>,
+
+    /// CA certificate (PEM) that signed the server certificate, for https addresses
+    #[structopt(long, parse(from_os_str))]
+    ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the server certificate, instead of --ca
+    #[structopt(long, conflicts_with = "ca")]
+    fingerprint: Option<String>,
+
+    /// Token (or HMAC key with --hmac) the server expects from this device
+    #[structopt(long, env = "CSI_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Sign every request with HMAC-SHA256 instead of sending the token;
+    /// the token may also come from the config file
+    #[structopt(long)]
+    hmac: bool,
+}
+
+impl Opt {
+    /// Take what was not given on the command line from the config file
+    fn apply(&mut self, file: config::Config) -> io::Result<()> {
+        if file.ca.is_some() && file.fingerprint.is_some() {
+            return Err(invalid("the config file sets both ca and fingerprint"));
+        }
+
+        self.addr = self.addr.take().or(file.addr);
+        self.device_id = self.device_id.take().or(file.device_id);
+        self.token = self.token.take().or(file.token);
+        self.hmac |= file.hmac == Some(true);
+
+        // --ca and --fingerprint exclude each other, a flag replaces both
+        if self.ca.is_none() && self.fingerprint.is_none() {
+            self.ca = file.ca;
+            self.fingerprint = file.fingerprint;
+        }
+
+        Ok(())
+    }
+}
+
+/// How requests are authenticated
+enum Auth {
+    None,
+    Token(String),
+    Hmac(String),
+}
+
+/// Router hostname, used as the default device id
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "recv_csi".to_string())
 }
 
 struct Processor {
     addr: String,
+    device_id: String,
+    auth: Auth,
     client: reqwest::blocking::Client,
 }
 
 impl Processor {
-    pub fn with_client(addr: String) -> Self {
+    pub fn with_client(addr: String, device_id: String, auth: Auth, client: reqwest::blocking::Client) -> Self {
         Self {
             addr: addr,
-            client: reqwest::blocking::Client::new(),
+            device_id: device_id,
+            auth: auth,
+            client: client,
         }
     }
     pub fn process_csi(&self, csi: &csi::CSI) {
         let data = bincode::serialize(&csi.to_ser())
             .unwrap();
             
-        let res = self.client.post(&self.addr)
-            .body(data)
-            .send();
+        let mut req = self.client.post(&self.addr)
+            .header("X-Device-Id", self.device_id.as_str());
+
+        match &self.auth {
+            Auth::None => {}
+            Auth::Token(token) => req = req.bearer_auth(token),
+            Auth::Hmac(key) => {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as i64);
+                let signature = csi::auth::sign(key.as_bytes(), &self.device_id, ts, &data);
+
+                req = req
+                    .header(csi::auth::TIMESTAMP_HEADER, ts.to_string())
+                    .header(csi::auth::SIGNATURE_HEADER, signature);
+            }
+        }
+
+        let res = req.body(data).send();
+        if let Ok(res) = res {
+            if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+                eprintln!("Server rejected the frame: {}", res.text().unwrap_or_default());
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// HTTP client for `addr`; https addresses need the server's CA or
+/// certificate fingerprint, no system roots are trusted
+#[cfg(feature = "tls")]
+fn http_client(addr: &str, opt: &Opt) -> io::Result<reqwest::blocking::Client> {
+    match tls::client_config(opt.ca.as_deref(), opt.fingerprint.as_deref())? {
+        Some(config) => reqwest::blocking::Client::builder()
+            .use_preconfigured_tls(config)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        None if is_https(addr) => Err(invalid("https needs --ca or --fingerprint")),
+        None => Ok(reqwest::blocking::Client::new()),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn http_client(addr: &str, opt: &Opt) -> io::Result<reqwest::blocking::Client> {
+    if is_https(addr) || opt.ca.is_some() || opt.fingerprint.is_some() {
+        return Err(invalid("https, --ca and --fingerprint need recv_csi built with --features tls"));
     }
+
+    Ok(reqwest::blocking::Client::new())
+}
+
+fn is_https(addr: &str) -> bool {
+    addr.get(..8).map_or(false, |scheme| scheme.eq_ignore_ascii_case("https://"))
 }
 
 fn ctrl_channel() -> Result<Receiver<()>, ctrlc::Error> {
@@ -54,7 +177,15 @@ fn ctrl_channel() -> Result<Receiver<()>, ctrlc::Error> {
 }
 
 fn main() -> Result<(), exitfailure::ExitFailure> {
-    let opt = Opt::from_args();
+    let mut opt = Opt::from_args();
+    if let Some(path) = opt.config.clone() {
+        opt.apply(config::Config::load(path)?)?;
+    }
+    if opt.hmac && opt.token.is_none() {
+        return Err(invalid("--hmac needs a --token").into());
+    }
+    let addr = opt.addr.clone().ok_or_else(|| invalid("--addr is required"))?;
+    let client = http_client(&addr, &opt)?;
 
     let ctrl_c_events = ctrl_channel()?;
     let ticks = tick(Duration::from_millis(0));
@@ -62,7 +193,13 @@ fn main() -> Result<(), exitfailure::ExitFailure> {
     let mut total_msg_cnt = 0;
     let mut csi = csi::CSI::with_file("/dev/CSI_dev");
 
-    let processor = Processor::with_client(opt.addr);
+    let device_id = opt.device_id.unwrap_or_else(hostname);
+    let auth = match opt.token {
+        Some(token) if opt.hmac => Auth::Hmac(token),
+        Some(token) => Auth::Token(token),
+        None => Auth::None,
+    };
+    let processor = Processor::with_client(addr, device_id, auth, client);
 
     loop {
         select! {
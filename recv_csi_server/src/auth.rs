@@ -0,0 +1,210 @@
+//! Authentication of the ingestion endpoints, see `csi_types::auth`
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use actix_web::{error, http::header, Error, HttpRequest};
+use chrono::prelude::*;
+use csi_types::auth::{self, MAX_SKEW_SECS, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+use crate::metrics;
+
+/// How receivers prove they hold their key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// `Authorization: Bearer <token>`
+    Token,
+    /// HMAC-SHA256 signature of every request
+    Hmac,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token" => Ok(Mode::Token),
+            "hmac" => Ok(Mode::Hmac),
+            _ => Err(format!("unknown auth mode '{}', expected token or hmac", s)),
+        }
+    }
+}
+
+/// Keys of the devices allowed to post
+#[derive(Clone, Debug, Default)]
+pub struct Keys {
+    devices: BTreeMap<String, String>,
+    /// Key of every device without its own
+    shared: Option<String>,
+}
+
+impl Keys {
+    pub fn shared(key: String) -> Self {
+        Keys {
+            devices: BTreeMap::new(),
+            shared: Some(key),
+        }
+    }
+
+    pub fn set_shared(&mut self, key: String) {
+        self.shared = Some(key);
+    }
+
+    /// Parse `<device> <key>` lines; `*` as the device sets the shared key,
+    /// empty lines and lines starting with `#` are skipped
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut keys = Keys::default();
+
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut it = line.split_whitespace();
+            match (it.next(), it.next(), it.next()) {
+                (Some("*"), Some(key), None) => keys.shared = Some(key.to_string()),
+                (Some(device), Some(key), None) => {
+                    keys.devices.insert(device.to_string(), key.to_string());
+                }
+                _ => return Err(format!("line {}: expected '<device> <key>'", n + 1)),
+            }
+        }
+
+        Ok(keys)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Keys::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn get(&self, device: &str) -> Option<&str> {
+        self.devices.get(device).or_else(|| self.shared.as_ref()).map(String::as_str)
+    }
+}
+
+/// Checks requests to `/csi` and `/post_xy`; lets everything through when
+/// no keys are configured
+#[derive(Clone, Debug)]
+pub struct Auth {
+    pub mode: Mode,
+    pub keys: Option<Keys>,
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+impl Auth {
+    fn verify(&self, keys: &Keys, req: &HttpRequest, device: &str, body: &[u8], now: DateTime<Utc>) -> Result<(), &'static str> {
+        let key = keys.get(device).ok_or("unknown device")?;
+
+        match self.mode {
+            Mode::Token => {
+                let token = header(req, header::AUTHORIZATION.as_str())
+                    .and_then(|v| v.strip_prefix("Bearer "))
+                    .ok_or("missing bearer token")?;
+
+                if auth::tokens_equal(token.trim(), key) {
+                    Ok(())
+                } else {
+                    Err("wrong token")
+                }
+            }
+            Mode::Hmac => {
+                let timestamp: i64 = header(req, TIMESTAMP_HEADER)
+                    .and_then(|v| v.trim().parse().ok())
+                    .ok_or("missing timestamp")?;
+                let signature = header(req, SIGNATURE_HEADER).ok_or("missing signature")?;
+
+                if now.timestamp().checked_sub(timestamp).map_or(true, |d| d.abs() > MAX_SKEW_SECS) {
+                    return Err("timestamp too far from server time");
+                }
+                if auth::verify(key.as_bytes(), device, timestamp, body, signature) {
+                    Ok(())
+                } else {
+                    Err("wrong signature")
+                }
+            }
+        }
+    }
+
+    /// Reject requests without valid credentials with `401 Unauthorized`
+    pub fn check(&self, req: &HttpRequest, device: &str, body: &[u8]) -> Result<(), Error> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return Ok(()),
+        };
+
+        self.verify(keys, req, device, body, Utc::now()).map_err(|reason| {
+            metrics::AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+            let peer = req.peer_addr().map_or_else(|| "unknown".to_string(), |a| a.to_string());
+            eprintln!("Rejected {} from {} ({}): {}", req.path(), device, peer, reason);
+
+            error::ErrorUnauthorized(reason)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const KEYS: &str = "# lab receivers\nlab-router-1 s3cret\n\n* shared\n";
+
+    #[test]
+    fn key_file() {
+        let keys = Keys::parse(KEYS).unwrap();
+        assert_eq!(keys.get("lab-router-1"), Some("s3cret"));
+        assert_eq!(keys.get("other"), Some("shared"));
+
+        assert!(Keys::parse("lab-router-1").is_err());
+        assert_eq!(Keys::parse("a b c").unwrap_err(), "line 1: expected '<device> <key>'");
+    }
+
+    #[test]
+    fn tokens() {
+        let auth = Auth { mode: Mode::Token, keys: Some(Keys::parse(KEYS).unwrap()) };
+        let now = Utc::now();
+        let check = |req: &HttpRequest, device| {
+            auth.verify(auth.keys.as_ref().unwrap(), req, device, b"", now)
+        };
+
+        let req = TestRequest::default().header("Authorization", "Bearer s3cret").to_http_request();
+        assert_eq!(check(&req, "lab-router-1"), Ok(()));
+        assert_eq!(check(&req, "lab-router-2"), Err("wrong token"));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(check(&req, "lab-router-1"), Err("missing bearer token"));
+
+        // no keys, no checks
+        assert!(Auth { mode: Mode::Token, keys: None }.check(&req, "x", b"").is_ok());
+    }
+
+    #[test]
+    fn signatures() {
+        let auth = Auth { mode: Mode::Hmac, keys: Some(Keys::shared("k".into())) };
+        let now = Utc::now();
+        let ts = now.timestamp();
+        let signed = |ts: i64, body: &[u8]| TestRequest::default()
+            .header(TIMESTAMP_HEADER, ts.to_string())
+            .header(SIGNATURE_HEADER, auth::sign(b"k", "dev", ts, body))
+            .to_http_request();
+        let check = |req: &HttpRequest, body: &[u8]| {
+            auth.verify(auth.keys.as_ref().unwrap(), req, "dev", body, now)
+        };
+
+        assert_eq!(check(&signed(ts, b"body"), b"body"), Ok(()));
+        assert_eq!(check(&signed(ts, b"body"), b"other"), Err("wrong signature"));
+        assert_eq!(check(&signed(ts - 600, b"body"), b"body"), Err("timestamp too far from server time"));
+        assert_eq!(check(&signed(i64::MIN, b"body"), b"body"), Err("timestamp too far from server time"));
+        assert_eq!(check(&signed(i64::MAX, b"body"), b"body"), Err("timestamp too far from server time"));
+    }
+}
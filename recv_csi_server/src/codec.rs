@@ -0,0 +1,158 @@
+use std::sync::atomic::Ordering;
+
+use actix_web::{error, http::header, web, Error, HttpRequest};
+use bytes::BytesMut;
+use csi_types::ser::{SerCSI, SerCSIv1};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+
+use crate::auth::Auth;
+use crate::metrics;
+
+pub const MAX_SIZE: usize = 262_144;
+
+/// Wire formats accepted by the ingestion endpoints
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Bincode,
+    Cbor,
+    Json,
+    MsgPack,
+}
+
+impl Format {
+    /// Map a media type (without parameters) to a format
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime {
+            "application/octet-stream"
+                | "application/bincode"
+                | "application/x-bincode" => Some(Format::Bincode),
+            "application/cbor" => Some(Format::Cbor),
+            "application/json" => Some(Format::Json),
+            "application/msgpack"
+                | "application/x-msgpack"
+                | "application/vnd.msgpack" => Some(Format::MsgPack),
+            _ => None,
+        }
+    }
+
+    /// Pick the decoder from the `Content-Type` header.
+    ///
+    /// Requests without the header are treated as bincode, which is what
+    /// `recv_csi` has always sent.
+    pub fn from_request(req: &HttpRequest) -> Result<Self, Error> {
+        let value = match req.headers().get(header::CONTENT_TYPE) {
+            Some(v) => v,
+            None => return Ok(Format::Bincode),
+        };
+
+        let value = value.to_str()
+            .map_err(|_| error::ErrorBadRequest("invalid Content-Type header"))?;
+        let mime = value.split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        Format::from_mime(&mime).ok_or_else(
+            || error::ErrorUnsupportedMediaType(format!("unsupported Content-Type: {}", mime))
+        )
+    }
+
+    /// `decode` for `/csi` bodies. Bincode bodies of receivers that predate
+    /// the frame payload lack its length and are read with the old layout.
+    pub fn decode_csi(self, body: &[u8]) -> Result<SerCSI, Error> {
+        if self == Format::Bincode {
+            if let Ok(csi) = bincode::deserialize::<SerCSI>(body) {
+                return Ok(csi);
+            }
+            if let Ok(v1) = bincode::deserialize::<SerCSIv1>(body) {
+                return Ok(v1.into());
+            }
+        }
+
+        self.decode(body)
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, Error> {
+        let res = match self {
+            Format::Bincode => bincode::deserialize(body).map_err(|e| e.to_string()),
+            Format::Cbor => serde_cbor::from_slice(body).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_read_ref(body).map_err(|e| e.to_string()),
+        };
+
+        res.map_err(|e| {
+            metrics::DECODE_FAILURES.fetch_add(1, Ordering::Relaxed);
+            error::ErrorBadRequest(format!("malformed {:?} body: {}", self, e))
+        })
+    }
+}
+
+/// Collect the request body, rejecting anything larger than `MAX_SIZE`
+pub async fn read_body(payload: web::Payload) -> Result<BytesMut, Error> {
+    read_body_max(payload, MAX_SIZE).await
+}
+
+/// `read_body` for endpoints taking files larger than `MAX_SIZE`
+pub async fn read_body_max(mut payload: web::Payload, max: usize) -> Result<BytesMut, Error> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        // limit max size of in-memory payload
+        if (body.len() + chunk.len()) > max {
+            metrics::PAYLOAD_TOO_LARGE.fetch_add(1, Ordering::Relaxed);
+            return Err(error::ErrorBadRequest("overflow"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// `decode_body` for `/csi`, see `Format::decode_csi`
+pub async fn decode_csi(req: &HttpRequest, payload: web::Payload, auth: &Auth, device: &str) -> Result<SerCSI, Error> {
+    let format = Format::from_request(req)?;
+    let body = read_body(payload).await?;
+    auth.check(req, device, &body)?;
+
+    format.decode_csi(&body)
+}
+
+/// Read the body, check the sender's credentials and decode it according to
+/// the request's `Content-Type`
+pub async fn decode_body<T: DeserializeOwned>(req: &HttpRequest, payload: web::Payload, auth: &Auth, device: &str) -> Result<T, Error> {
+    let format = Format::from_request(req)?;
+    let body = read_body(payload).await?;
+    auth.check(req, device, &body)?;
+
+    format.decode(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use csi_types::ser::ComplexDef;
+    use csi_types::CSIStruct;
+
+    use super::*;
+
+    #[test]
+    fn legacy_bincode() {
+        let mut csi_status = CSIStruct::new();
+        csi_status.nr = 2;
+        csi_status.payload_len = 3;
+        let csi_matrix = vec![vec![vec![ComplexDef { re: 1, im: -1 }; 4]; 1]; 2];
+
+        let old = bincode::serialize(&SerCSIv1 { csi_matrix: csi_matrix.clone(), csi_status: csi_status.clone() }).unwrap();
+        let csi = Format::Bincode.decode_csi(&old).unwrap();
+        assert_eq!(csi.csi_matrix, csi_matrix);
+        assert_eq!(csi.csi_status.nr, 2);
+        assert!(csi.payload.is_empty());
+
+        let new = bincode::serialize(&SerCSI { csi_matrix, csi_status, payload: vec![1, 2, 3] }).unwrap();
+        assert_eq!(Format::Bincode.decode_csi(&new).unwrap().payload, vec![1, 2, 3]);
+
+        assert!(Format::Bincode.decode_csi(&old[..old.len() - 1]).is_err());
+    }
+}
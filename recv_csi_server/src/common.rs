@@ -1,39 +1,10 @@
-use std::fs::File;
-use chrono::prelude::Utc;
+use chrono::prelude::*;
 
-use crate::types::Sample;
-
-pub fn save_collected(csi: &Vec<Sample>) {
-    let date = match csi.first() {
-        Some(s) => s.date,
-        None => Utc::now(),
-    };
-    let fname = format!("csi_data_{}.csv", date);
-
-    let output = File::create(&fname).unwrap();
-    let mut wtr = csv::Writer::from_writer(output);
-
-    for r in csi {
-        wtr.write_record(
-            [
-                vec![
-                    format!("{}", r.date),
-                    format!("{}", r.x),
-                    format!("{}", r.y),
-                ],
-                r.csi[0][0].iter().map(ToString::to_string).collect(),
-                r.csi[0][1].iter().map(ToString::to_string).collect(),
-                r.csi[1][0].iter().map(ToString::to_string).collect(),
-                r.csi[1][1].iter().map(ToString::to_string).collect(),
-            ].concat()
-        ).unwrap();
-    }
-
-    wtr.flush()
-        .unwrap();
+/// Timestamp usable in file names on every filesystem: no spaces or colons
+pub fn file_timestamp(date: DateTime<Utc>) -> String {
+    date.format("%Y%m%dT%H%M%S%.3fZ").to_string()
 }
 
-
 // use crate::types::CSIData;
 
 // pub fn save_collected_(csi: &CSIData) {
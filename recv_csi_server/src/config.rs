@@ -0,0 +1,206 @@
+//! TOML configuration file, `--config`
+//!
+//! Every section and key is optional; flags given on the command line take
+//! precedence over the file. Unknown keys are an error, so a typo does not
+//! silently fall back to a default.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::auth::Mode;
+use crate::export::csv::{MetaSet, Pairs, Values};
+use crate::storage::Backend;
+
+/// Values of the types parsed from flags are written the same way in the file
+fn parsed<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(d)? {
+        Some(s) => s.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub addr: Option<String>,
+    pub shutdown_timeout: Option<u64>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Csv {
+    #[serde(deserialize_with = "parsed")]
+    pub pairs: Option<Pairs>,
+    #[serde(deserialize_with = "parsed")]
+    pub values: Option<Values>,
+    #[serde(deserialize_with = "parsed")]
+    pub meta: Option<MetaSet>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Output {
+    /// `false` is `--no-output`
+    pub enabled: Option<bool>,
+    pub dir: Option<PathBuf>,
+    pub write_at_least: Option<usize>,
+    pub rotate_mb: Option<u64>,
+    pub rotate_minutes: Option<u64>,
+    pub flush_secs: Option<u64>,
+    pub checkpoint_secs: Option<u64>,
+    pub csv: Csv,
+    pub parquet_dir: Option<PathBuf>,
+    pub parquet_row_group: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    #[serde(deserialize_with = "parsed")]
+    pub backend: Option<Backend>,
+    pub db: Option<PathBuf>,
+    pub db_batch: Option<usize>,
+    pub spill_dir: Option<PathBuf>,
+    pub spill_segment: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    pub keep_samples: Option<usize>,
+    pub keep_minutes: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    #[serde(deserialize_with = "parsed")]
+    pub mode: Option<Mode>,
+    pub token: Option<String>,
+    pub keys: Option<PathBuf>,
+}
+
+/// Session started with the server, `--session`, `-p`, `-x` and `-y`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Session {
+    pub name: Option<String>,
+    pub present: Option<bool>,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+}
+
+/// Site survey, `--survey` and the `--survey-*` flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Survey {
+    pub points: Option<PathBuf>,
+    pub samples: Option<usize>,
+    pub secs: Option<u64>,
+    pub min_samples: Option<usize>,
+    pub min_rssi: Option<u8>,
+    pub max_bad: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub output: Output,
+    pub storage: Storage,
+    pub retention: Retention,
+    pub auth: Auth,
+    pub session: Session,
+    pub survey: Survey,
+}
+
+impl Config {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        Config::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full() {
+        let c = Config::parse(r#"
+            [server]
+            addr = "0.0.0.0:8899"
+
+            [output]
+            dir = "out"
+            rotate_mb = 100
+
+            [output.csv]
+            pairs = "0:0,1:1"
+            values = "both"
+
+            [storage]
+            backend = "file"
+            spill_dir = "spill"
+
+            [retention]
+            keep_minutes = 60
+
+            [auth]
+            mode = "hmac"
+            keys = "keys.txt"
+
+            [session]
+            name = "empty-room"
+            present = true
+            x = 1.5
+            y = 2.0
+
+            [survey]
+            points = "grid.csv"
+            samples = 200
+        "#).unwrap();
+
+        assert_eq!(c.server.addr.as_deref(), Some("0.0.0.0:8899"));
+        assert_eq!(c.output.rotate_mb, Some(100));
+        assert_eq!(c.output.csv.values, Some(Values::Both));
+        assert!(c.output.csv.meta.is_none());
+        assert_eq!(c.storage.backend, Some(Backend::File));
+        assert_eq!(c.auth.mode, Some(Mode::Hmac));
+        assert_eq!(c.session.x, Some(1.5));
+        assert_eq!(c.survey.samples, Some(200));
+        assert!(c.survey.secs.is_none());
+    }
+
+    #[test]
+    fn empty() {
+        let c = Config::parse("").unwrap();
+        assert!(c.server.addr.is_none());
+        assert!(c.output.csv.pairs.is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Config::parse("[server]\nport = 8899\n").unwrap_err().contains("port"));
+        assert!(Config::parse("[sever]\naddr = \"x\"\n").unwrap_err().contains("sever"));
+        assert!(Config::parse("[auth]\nmode = \"basic\"\n").unwrap_err().contains("basic"));
+        assert!(Config::parse("[output]\nrotate_mb = \"big\"\n").is_err());
+    }
+}
@@ -0,0 +1,662 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+
+use chrono::prelude::*;
+use csi_types::ser::ComplexDef;
+use csi_types::CSIStruct;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+
+use crate::storage::{no_annotation, session_exists, SessionInfo, Storage};
+use crate::store::Filter;
+use crate::trajectory::{TrackPoint, Trajectory};
+use crate::types::{Annotation, DeviceStats, Receive, Sample, Session};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    stopped INTEGER,
+    present INTEGER NOT NULL DEFAULT 0,
+    x REAL,
+    y REAL
+);
+
+CREATE TABLE IF NOT EXISTS devices (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    frames INTEGER NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS annotations (
+    id INTEGER PRIMARY KEY,
+    start INTEGER NOT NULL,
+    end INTEGER,
+    device TEXT,
+    session TEXT,
+    x REAL,
+    y REAL,
+    z REAL,
+    heading REAL,
+    activity TEXT,
+    subject TEXT,
+    tags TEXT NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS trajectories (
+    session TEXT PRIMARY KEY,
+    offset_ms INTEGER NOT NULL,
+    max_gap_ms INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS trajectory_points (
+    session TEXT NOT NULL,
+    t INTEGER NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    z REAL,
+    heading REAL
+);
+
+CREATE TABLE IF NOT EXISTS positions (
+    id INTEGER PRIMARY KEY,
+    date INTEGER NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS samples (
+    id INTEGER PRIMARY KEY,
+    date INTEGER NOT NULL,
+    device_id INTEGER NOT NULL REFERENCES devices(id),
+    session_id INTEGER REFERENCES sessions(id),
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    tstamp INTEGER NOT NULL,
+    channel INTEGER NOT NULL,
+    chan_bw INTEGER NOT NULL,
+    rate INTEGER NOT NULL,
+    nr INTEGER NOT NULL,
+    nc INTEGER NOT NULL,
+    num_tones INTEGER NOT NULL,
+    noise INTEGER NOT NULL,
+    phyerr INTEGER NOT NULL,
+    rssi INTEGER NOT NULL,
+    rssi_0 INTEGER NOT NULL,
+    rssi_1 INTEGER NOT NULL,
+    rssi_2 INTEGER NOT NULL,
+    payload_len INTEGER NOT NULL,
+    csi_len INTEGER NOT NULL,
+    buf_len INTEGER NOT NULL,
+    csi BLOB NOT NULL,
+    payload BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS samples_date ON samples(date);
+CREATE INDEX IF NOT EXISTS samples_device_date ON samples(device_id, date);
+CREATE INDEX IF NOT EXISTS positions_date ON positions(date);
+CREATE INDEX IF NOT EXISTS samples_session ON samples(session_id);
+CREATE INDEX IF NOT EXISTS trajectory_points_session_t ON trajectory_points(session, t);
+";
+
+const SAMPLE_COLUMNS: &str = "
+    s.date, d.name, s.x, s.y, s.tstamp, s.channel, s.chan_bw, s.rate, s.nr, s.nc,
+    s.num_tones, s.noise, s.phyerr, s.rssi, s.rssi_0, s.rssi_1, s.rssi_2,
+    s.payload_len, s.csi_len, s.buf_len, s.csi, s.payload, se.name";
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Microseconds since the Unix epoch
+fn micros(date: DateTime<Utc>) -> i64 {
+    date.timestamp() * 1_000_000 + date.timestamp_subsec_micros() as i64
+}
+
+fn from_micros(us: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(us.div_euclid(1_000_000), (us.rem_euclid(1_000_000) * 1000) as u32).unwrap()
+}
+
+/// Pack the used part of a CSI matrix: `nr`, `nc`, `tones` as `u16`,
+/// then `re`, `im` pairs as `i16`, all little endian, `[rx][tx][tone]` order.
+/// Values that do not fit are an error rather than being truncated.
+pub fn encode_csi(s: &Sample) -> io::Result<Vec<u8>> {
+    let too_large = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("csi {} does not fit the blob", what));
+
+    let (nr, nc, tones) = s.dims();
+    let mut out = Vec::with_capacity(6 + nr * nc * tones * 4);
+    for d in &[nr, nc, tones] {
+        let d = u16::try_from(*d).map_err(|_| too_large("dimension"))?;
+        out.extend_from_slice(&d.to_le_bytes());
+    }
+    for row in &s.csi[..nr] {
+        for col in &row[..nc] {
+            for c in &col[..tones] {
+                for v in &[c.re, c.im] {
+                    let v = i16::try_from(*v).map_err(|_| too_large("value"))?;
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+pub fn decode_csi(blob: &[u8]) -> io::Result<Vec<Vec<Vec<ComplexDef<isize>>>>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed csi blob");
+    let u16_at = |i: usize| blob.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let i16_at = |i: usize| blob.get(i..i + 2).map(|b| i16::from_le_bytes([b[0], b[1]]) as isize);
+
+    let dims = (u16_at(0), u16_at(2), u16_at(4));
+    let (nr, nc, tones) = match dims {
+        (Some(nr), Some(nc), Some(tones)) => (nr as usize, nc as usize, tones as usize),
+        _ => return Err(invalid()),
+    };
+    if blob.len() != 6 + nr * nc * tones * 4 {
+        return Err(invalid());
+    }
+
+    let mut i = 6;
+    let mut csi = Vec::with_capacity(nr);
+    for _ in 0..nr {
+        let mut row = Vec::with_capacity(nc);
+        for _ in 0..nc {
+            let mut col = Vec::with_capacity(tones);
+            for _ in 0..tones {
+                col.push(ComplexDef {
+                    re: i16_at(i).ok_or_else(invalid)?,
+                    im: i16_at(i + 2).ok_or_else(invalid)?,
+                });
+                i += 4;
+            }
+            row.push(col);
+        }
+        csi.push(row);
+    }
+
+    Ok(csi)
+}
+
+fn sample_from_row(row: &Row) -> rusqlite::Result<Sample> {
+    let status = CSIStruct {
+        tstamp: row.get::<_, i64>(4)? as u64,
+        channel: row.get(5)?,
+        chanBW: row.get(6)?,
+        rate: row.get(7)?,
+        nr: row.get(8)?,
+        nc: row.get(9)?,
+        num_tones: row.get(10)?,
+        noise: row.get(11)?,
+        phyerr: row.get(12)?,
+        rssi: row.get(13)?,
+        rssi_0: row.get(14)?,
+        rssi_1: row.get(15)?,
+        rssi_2: row.get(16)?,
+        payload_len: row.get(17)?,
+        csi_len: row.get(18)?,
+        buf_len: row.get(19)?,
+    };
+    let csi = decode_csi(&row.get::<_, Vec<u8>>(20)?).map_err(
+        |e| rusqlite::Error::FromSqlConversionFailure(20, Type::Blob, Box::new(e))
+    )?;
+
+    Ok(Sample {
+        date: from_micros(row.get(0)?),
+        device: row.get(1)?,
+        x: row.get(2)?,
+        y: row.get(3)?,
+        csi,
+        status,
+        payload: row.get(21)?,
+        session: row.get(22)?,
+    })
+}
+
+const ANNOTATION_COLUMNS: &str = "id, start, end, device, session, x, y, z, heading, activity, subject, tags";
+
+fn annotation_from_row(row: &Row) -> rusqlite::Result<Annotation> {
+    let tags: String = row.get(11)?;
+
+    Ok(Annotation {
+        id: row.get::<_, i64>(0)? as u64,
+        start: from_micros(row.get(1)?),
+        end: row.get::<_, Option<i64>>(2)?.map(from_micros),
+        device: row.get(3)?,
+        session: row.get(4)?,
+        x: row.get(5)?,
+        y: row.get(6)?,
+        z: row.get(7)?,
+        heading: row.get(8)?,
+        activity: row.get(9)?,
+        subject: row.get(10)?,
+        tags: serde_json::from_str(&tags)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(11, Type::Text, Box::new(e)))?,
+    })
+}
+
+/// SQLite storage of samples, devices and positions.
+///
+/// Samples are buffered and inserted in one transaction per `batch`
+/// samples, or on `flush`. Reads flush first, so they always see every
+/// accepted sample.
+pub struct Db {
+    conn: Connection,
+    batch: usize,
+    /// Samples not inserted yet, with their encoded CSI
+    pending: Vec<(Sample, Vec<u8>)>,
+}
+
+impl Db {
+    pub fn open<P: AsRef<Path>>(path: P, batch: usize) -> io::Result<Self> {
+        let conn = Connection::open(path).map_err(to_io)?;
+        // WAL keeps readers and the writer out of each other's way and makes
+        // a commit a single sequential write
+        conn.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(())).map_err(to_io)?;
+        conn.execute_batch("PRAGMA synchronous = NORMAL;").map_err(to_io)?;
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+
+        Ok(Self {
+            conn,
+            batch: batch.max(1),
+            pending: vec![],
+        })
+    }
+
+    pub fn push(&mut self, s: Sample) -> io::Result<()> {
+        // a matrix that cannot be stored is refused here, not at the flush
+        let csi = encode_csi(&s)?;
+        self.pending.push((s, csi));
+        if self.pending.len() >= self.batch {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert buffered samples and update device statistics in one transaction
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let tx = self.conn.transaction().map_err(to_io)?;
+        {
+            let mut device = tx.prepare_cached(
+                "INSERT INTO devices (name, frames, first_seen, last_seen) VALUES (?1, 1, ?2, ?2)
+                 ON CONFLICT(name) DO UPDATE SET frames = frames + 1, last_seen = excluded.last_seen"
+            ).map_err(to_io)?;
+            let mut sample = tx.prepare_cached(
+                "INSERT INTO samples (
+                    date, device_id, x, y, tstamp, channel, chan_bw, rate, nr, nc,
+                    num_tones, noise, phyerr, rssi, rssi_0, rssi_1, rssi_2,
+                    payload_len, csi_len, buf_len, csi, payload, session_id
+                 ) VALUES (
+                    ?1, (SELECT id FROM devices WHERE name = ?2), ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10,
+                    ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22,
+                    (SELECT id FROM sessions WHERE name = ?23)
+                 )"
+            ).map_err(to_io)?;
+
+            for (s, csi) in &self.pending {
+                let st = &s.status;
+                device.execute(params![s.device, micros(s.date)]).map_err(to_io)?;
+                sample.execute(params![
+                    micros(s.date), s.device, s.x, s.y, st.tstamp as i64, st.channel, st.chanBW,
+                    st.rate, st.nr, st.nc, st.num_tones, st.noise, st.phyerr, st.rssi,
+                    st.rssi_0, st.rssi_1, st.rssi_2, st.payload_len, st.csi_len, st.buf_len,
+                    csi, s.payload, s.session,
+                ]).map_err(to_io)?;
+            }
+        }
+        tx.commit().map_err(to_io)?;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    pub fn add_position(&mut self, date: DateTime<Utc>, x: f64, y: f64) -> io::Result<()> {
+        self.conn.execute(
+            "INSERT INTO positions (date, x, y) VALUES (?1, ?2, ?3)",
+            params![micros(date), x, y],
+        ).map_err(to_io)?;
+
+        Ok(())
+    }
+
+    /// The most recently recorded position
+    pub fn last_position(&self) -> io::Result<Option<(f64, f64)>> {
+        let mut stmt = self.conn.prepare("SELECT x, y FROM positions ORDER BY date DESC LIMIT 1")
+            .map_err(to_io)?;
+        let mut rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(to_io)?;
+
+        rows.next().transpose().map_err(to_io)
+    }
+
+    pub fn devices(&mut self) -> io::Result<BTreeMap<String, DeviceStats>> {
+        self.flush()?;
+
+        let mut stmt = self.conn.prepare("SELECT name, frames, first_seen, last_seen FROM devices")
+            .map_err(to_io)?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            Ok((row.get::<_, String>(0)?, DeviceStats {
+                frames: row.get::<_, i64>(1)? as usize,
+                first_seen: from_micros(row.get(2)?),
+                last_seen: from_micros(row.get(3)?),
+            }))
+        }).map_err(to_io)?;
+
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn select(&self, clause: &str, params: &[&dyn rusqlite::ToSql]) -> io::Result<Vec<Sample>> {
+        self.select_rows(SAMPLE_COLUMNS, clause, params, sample_from_row)
+    }
+
+    fn select_rows<T, F>(&self, columns: &str, clause: &str, params: &[&dyn rusqlite::ToSql], f: F) -> io::Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> rusqlite::Result<T>,
+    {
+        let sql = format!(
+            "SELECT {} FROM samples s JOIN devices d ON d.id = s.device_id
+             LEFT JOIN sessions se ON se.id = s.session_id {}",
+            columns, clause,
+        );
+        let mut stmt = self.conn.prepare(&sql).map_err(to_io)?;
+        let rows = stmt.query_map(params, f).map_err(to_io)?;
+
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    /// Matching samples, oldest first
+    pub fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        self.query_page(filter, 0, usize::MAX)
+    }
+
+    /// Like `query`, skipping `skip` samples and returning at most `limit`
+    pub fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        self.select_matches(filter, SAMPLE_COLUMNS, "ORDER BY s.date, s.id", skip, limit, sample_from_row)
+    }
+
+    /// The newest `limit` matches of `query`, oldest first
+    pub fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        let mut res = self.select_matches(filter, SAMPLE_COLUMNS, "ORDER BY s.date DESC, s.id DESC", 0, limit, sample_from_row)?;
+        res.reverse();
+
+        Ok(res)
+    }
+
+    /// Capture times of `query`, without reading the CSI
+    pub fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        self.select_matches(filter, "s.date", "ORDER BY s.date, s.id", 0, usize::MAX, |row| row.get(0).map(from_micros))
+    }
+
+    fn select_matches<T, F>(&mut self, filter: &Filter, columns: &str, order: &str, skip: usize, limit: usize, f: F) -> io::Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> rusqlite::Result<T>,
+    {
+        self.flush()?;
+
+        let from = filter.from.map_or(i64::MIN, micros);
+        let to = filter.to.map_or(i64::MAX, micros);
+        let skip = skip.min(i64::MAX as usize) as i64;
+        let limit = limit.min(i64::MAX as usize) as i64;
+
+        let mut clause = "WHERE s.date BETWEEN ?1 AND ?2".to_string();
+        let mut params: Vec<&dyn rusqlite::ToSql> = vec![&from, &to];
+        if let Some(device) = &filter.device {
+            params.push(device);
+            clause += &format!(" AND d.name = ?{}", params.len());
+        }
+        if let Some(session) = &filter.session {
+            params.push(session);
+            clause += &format!(" AND se.name = ?{}", params.len());
+        }
+        clause += &format!(" {} LIMIT ?{} OFFSET ?{}", order, params.len() + 1, params.len() + 2);
+        params.push(&limit);
+        params.push(&skip);
+
+        self.select_rows(columns, &clause, &params, f)
+    }
+
+    /// The most recent sample of a device, or of any device if `None`
+    pub fn last(&mut self, device: Option<&str>) -> io::Result<Option<Sample>> {
+        self.flush()?;
+
+        let res = match device {
+            Some(device) => self.select("WHERE d.name = ?1 ORDER BY s.date DESC, s.id DESC LIMIT 1", &[&device])?,
+            None => self.select("ORDER BY s.date DESC, s.id DESC LIMIT 1", &[])?,
+        };
+
+        Ok(res.into_iter().next())
+    }
+}
+
+impl Storage for Db {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn append(&mut self, sample: Sample) -> io::Result<()> {
+        self.push(sample)
+    }
+
+    fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        Db::query(self, filter)
+    }
+
+    fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        Db::query_page(self, filter, skip, limit)
+    }
+
+    fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        Db::query_last(self, filter, limit)
+    }
+
+    fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        Db::dates(self, filter)
+    }
+
+    fn latest(&mut self, device: Option<&str>) -> io::Result<Option<Sample>> {
+        self.last(device)
+    }
+
+    fn start_session(&mut self, session: &Session) -> io::Result<()> {
+        let exists: i64 = self.conn.query_row(
+            "SELECT count(*) FROM sessions WHERE name = ?1", params![session.name], |row| row.get(0),
+        ).map_err(to_io)?;
+        if exists > 0 {
+            return Err(session_exists(&session.name));
+        }
+
+        let (x, y) = match session.position {
+            Receive::Predefined(x, y) => (Some(x), Some(y)),
+            Receive::Realtime => (None, None),
+        };
+        self.conn.execute(
+            "INSERT INTO sessions (name, started, present, x, y) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![session.name, micros(session.started), session.present, x, y],
+        ).map_err(to_io)?;
+
+        Ok(())
+    }
+
+    fn stop_session(&mut self, name: &str, date: DateTime<Utc>) -> io::Result<()> {
+        self.conn.execute(
+            "UPDATE sessions SET stopped = ?2 WHERE name = ?1 AND stopped IS NULL",
+            params![name, micros(date)],
+        ).map_err(to_io)?;
+
+        Ok(())
+    }
+
+    fn sessions(&mut self) -> io::Result<Vec<SessionInfo>> {
+        self.flush()?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT name, started, stopped, present, x, y,
+                    (SELECT count(*) FROM samples WHERE session_id = sessions.id)
+             FROM sessions ORDER BY started"
+        ).map_err(to_io)?;
+        let rows = stmt.query_map(NO_PARAMS, |row| {
+            let position = match (row.get(4)?, row.get(5)?) {
+                (Some(x), Some(y)) => Receive::Predefined(x, y),
+                _ => Receive::Realtime,
+            };
+
+            Ok(SessionInfo {
+                session: Session {
+                    name: row.get(0)?,
+                    started: from_micros(row.get(1)?),
+                    present: row.get(3)?,
+                    position,
+                },
+                stopped: row.get::<_, Option<i64>>(2)?.map(from_micros),
+                samples: row.get::<_, i64>(6)? as usize,
+            })
+        }).map_err(to_io)?;
+
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn add_annotation(&mut self, mut annotation: Annotation) -> io::Result<Annotation> {
+        let tags = serde_json::to_string(&annotation.tags)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let a = &annotation;
+        self.conn.execute(
+            "INSERT INTO annotations (start, end, device, session, x, y, z, heading, activity, subject, tags)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                micros(a.start), a.end.map(micros), a.device, a.session, a.x, a.y, a.z,
+                a.heading, a.activity, a.subject, tags,
+            ],
+        ).map_err(to_io)?;
+        annotation.id = self.conn.last_insert_rowid() as u64;
+
+        Ok(annotation)
+    }
+
+    fn end_annotation(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation> {
+        let mut annotation = self.conn.query_row(
+            &format!("SELECT {} FROM annotations WHERE id = ?1", ANNOTATION_COLUMNS),
+            params![id as i64],
+            annotation_from_row,
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => no_annotation(id),
+            e => to_io(e),
+        })?;
+        if end < annotation.start {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "end is before start"));
+        }
+
+        self.conn.execute(
+            "UPDATE annotations SET end = ?2 WHERE id = ?1",
+            params![id as i64, micros(end)],
+        ).map_err(to_io)?;
+        annotation.end = Some(end);
+
+        Ok(annotation)
+    }
+
+    fn delete_annotation(&mut self, id: u64) -> io::Result<()> {
+        let n = self.conn.execute("DELETE FROM annotations WHERE id = ?1", params![id as i64])
+            .map_err(to_io)?;
+        if n == 0 {
+            return Err(no_annotation(id));
+        }
+
+        Ok(())
+    }
+
+    fn annotations(&mut self) -> io::Result<Vec<Annotation>> {
+        let mut stmt = self.conn.prepare(
+            &format!("SELECT {} FROM annotations ORDER BY start, id", ANNOTATION_COLUMNS)
+        ).map_err(to_io)?;
+        let rows = stmt.query_map(NO_PARAMS, annotation_from_row).map_err(to_io)?;
+
+        rows.collect::<rusqlite::Result<_>>().map_err(to_io)
+    }
+
+    fn set_trajectory(&mut self, session: &str, trajectory: &Trajectory) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(to_io)?;
+        tx.execute("DELETE FROM trajectory_points WHERE session = ?1", params![session]).map_err(to_io)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO trajectories (session, offset_ms, max_gap_ms) VALUES (?1, ?2, ?3)",
+            params![session, trajectory.offset_ms, trajectory.max_gap_ms],
+        ).map_err(to_io)?;
+        {
+            let mut point = tx.prepare_cached(
+                "INSERT INTO trajectory_points (session, t, x, y, z, heading) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+            ).map_err(to_io)?;
+            for p in &trajectory.points {
+                point.execute(params![session, micros(p.t), p.x, p.y, p.z, p.heading]).map_err(to_io)?;
+            }
+        }
+
+        tx.commit().map_err(to_io)
+    }
+
+    fn trajectory(&mut self, session: &str) -> io::Result<Option<Trajectory>> {
+        let settings = self.conn.query_row(
+            "SELECT offset_ms, max_gap_ms FROM trajectories WHERE session = ?1",
+            params![session],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        );
+        let (offset_ms, max_gap_ms) = match settings {
+            Ok(settings) => settings,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+            Err(e) => return Err(to_io(e)),
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT t, x, y, z, heading FROM trajectory_points WHERE session = ?1 ORDER BY t"
+        ).map_err(to_io)?;
+        let rows = stmt.query_map(params![session], |row| Ok(TrackPoint {
+            t: from_micros(row.get(0)?),
+            x: row.get(1)?,
+            y: row.get(2)?,
+            z: row.get(3)?,
+            heading: row.get(4)?,
+        })).map_err(to_io)?;
+
+        Ok(Some(Trajectory {
+            offset_ms,
+            max_gap_ms,
+            points: rows.collect::<rusqlite::Result<_>>().map_err(to_io)?,
+        }))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Db::flush(self)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Db::flush(self)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Db::flush(self)
+    }
+
+    fn add_position(&mut self, date: DateTime<Utc>, x: f64, y: f64) -> io::Result<()> {
+        Db::add_position(self, date, x, y)
+    }
+
+    fn last_position(&mut self) -> io::Result<Option<(f64, f64)>> {
+        Db::last_position(self)
+    }
+
+    fn devices(&mut self) -> io::Result<BTreeMap<String, DeviceStats>> {
+        Db::devices(self)
+    }
+
+    fn in_memory(&self) -> usize {
+        self.pending.len()
+    }
+}
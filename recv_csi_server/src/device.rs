@@ -0,0 +1,35 @@
+use actix_web::HttpRequest;
+
+/// Header a receiver uses to identify itself (device id, hostname or MAC)
+pub const DEVICE_HEADER: &str = "x-device-id";
+
+/// Fallback id for requests that carry neither the header nor a peer address
+pub const UNKNOWN_DEVICE: &str = "unknown";
+
+/// Identify the sender of a request.
+///
+/// Prefers the `X-Device-Id` header and falls back to the peer IP address,
+/// so receivers that predate the header still get separate streams.
+pub fn device_id(req: &HttpRequest) -> String {
+    let from_header = req.headers()
+        .get(DEVICE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+
+    if let Some(id) = from_header {
+        return id.to_string();
+    }
+
+    match req.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => UNKNOWN_DEVICE.to_string(),
+    }
+}
+
+/// Make a device id usable as a part of a file name
+pub fn sanitize(device: &str) -> String {
+    device.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,167 @@
+//! Server-Sent Events feed of per-device statistics for `/events`
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, Error, HttpResponse};
+use bytes::Bytes;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+use crate::types::CSIData;
+
+/// Milliseconds between events unless `interval_ms` says otherwise
+pub const DEFAULT_INTERVAL_MS: u64 = 1000;
+const MIN_INTERVAL_MS: u64 = 100;
+/// Values in the downsampled amplitude vector unless `points` says otherwise
+pub const DEFAULT_POINTS: usize = 16;
+
+/// Query string of `/events`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Params {
+    pub device: Option<String>,
+    pub interval_ms: Option<u64>,
+    /// Length of the amplitude vector
+    pub points: Option<usize>,
+}
+
+/// One `device` event
+#[derive(Clone, Debug, Serialize)]
+struct DeviceEvent {
+    device: String,
+    frames: usize,
+    /// Frames per second since the previous event
+    rate: f64,
+    last_seen: DateTime<Utc>,
+    rssi: Option<u8>,
+    /// Amplitude of the latest sample, averaged over antenna pairs and
+    /// groups of adjacent tones
+    amplitude: Option<Vec<f64>>,
+}
+
+/// Average `values` down to at most `points` buckets of adjacent values
+pub fn downsample(values: &[f64], points: usize) -> Vec<f64> {
+    if points == 0 || values.len() <= points {
+        return values.to_vec();
+    }
+
+    (0..points)
+        .map(|i| {
+            let bucket = &values[i * values.len() / points..(i + 1) * values.len() / points];
+            bucket.iter().sum::<f64>() / bucket.len() as f64
+        })
+        .collect()
+}
+
+/// Per-tone amplitude averaged over all antenna pairs
+fn mean_amplitude(amplitude: &[Vec<Vec<f64>>]) -> Vec<f64> {
+    let rows: Vec<&Vec<f64>> = amplitude.iter().flatten().collect();
+    let tones = rows.first().map_or(0, |r| r.len());
+
+    (0..tones)
+        .map(|k| rows.iter().map(|r| r[k]).sum::<f64>() / rows.len() as f64)
+        .collect()
+}
+
+/// State of one subscriber between events
+struct Feed {
+    params: Params,
+    /// Frame counters at the previous event
+    frames: BTreeMap<String, usize>,
+    last: Instant,
+}
+
+impl Feed {
+    /// `device` events for everything received up to now
+    fn next(&mut self, x: &CSIData) -> io::Result<String> {
+        let now = Instant::now();
+        let secs = (now - self.last).as_secs_f64();
+        self.last = now;
+
+        let points = self.params.points.unwrap_or(DEFAULT_POINTS);
+        let mut out = String::new();
+
+        let devices: Vec<_> = x.devices.iter()
+            .filter(|(id, _)| self.params.device.as_ref().map_or(true, |d| d == *id))
+            .map(|(id, stats)| (id.clone(), stats.clone()))
+            .collect();
+        for (id, stats) in devices {
+            let before = self.frames.insert(id.clone(), stats.frames).unwrap_or(stats.frames);
+            let latest = x.latest.get(&id);
+
+            let event = DeviceEvent {
+                rate: if secs > 0.0 { (stats.frames - before) as f64 / secs } else { 0.0 },
+                frames: stats.frames,
+                last_seen: stats.last_seen,
+                rssi: latest.as_ref().map(|s| s.status.rssi),
+                amplitude: latest.map(|s| downsample(&mean_amplitude(&s.amplitude()), points)),
+                device: id,
+            };
+
+            out.push_str("event: device\ndata: ");
+            out.push_str(&serde_json::to_string(&event).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?);
+            out.push_str("\n\n");
+        }
+
+        // keeps the connection from being closed by proxies while idle
+        if out.is_empty() {
+            out.push_str(": idle\n\n");
+        }
+
+        Ok(out)
+    }
+}
+
+/// Stream `device` events every `interval_ms` until the client goes away
+pub fn start(params: Params, state: web::Data<Mutex<CSIData>>) -> HttpResponse {
+    let every = Duration::from_millis(params.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS).max(MIN_INTERVAL_MS));
+
+    // the first event only has a rate for devices seen from now on
+    let frames = metrics::lock(&state).devices.iter()
+        .map(|(id, s)| (id.clone(), s.frames))
+        .collect();
+    let feed = Feed {
+        params,
+        frames,
+        last: Instant::now(),
+    };
+
+    let events = futures::stream::unfold(
+        (feed, actix_rt::time::interval(every), state),
+        |(mut feed, mut ticks, state)| async move {
+            ticks.tick().await;
+
+            let body = feed.next(&metrics::lock(&state)).map_err(Error::from);
+            Some((body.map(Bytes::from), (feed, ticks, state)))
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(Box::pin(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsampling() {
+        let v: Vec<f64> = (0..8).map(f64::from).collect();
+        assert_eq!(downsample(&v, 4), vec![0.5, 2.5, 4.5, 6.5]);
+        assert_eq!(downsample(&v, 3), vec![0.5, 3.0, 6.0]);
+        assert_eq!(downsample(&v, 16), v);
+        assert_eq!(downsample(&v, 0), v);
+    }
+
+    #[test]
+    fn pairs_are_averaged() {
+        let amplitude = vec![vec![vec![1.0, 2.0], vec![3.0, 4.0]]];
+        assert_eq!(mean_amplitude(&amplitude), vec![2.0, 3.0]);
+        assert!(mean_amplitude(&[]).is_empty());
+    }
+}
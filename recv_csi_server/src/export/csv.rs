@@ -0,0 +1,305 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use csi_types::CSIStruct;
+use csi_types::ser::{abs, phase, ComplexDef};
+use serde::{Deserialize, Serialize};
+
+use super::Truth;
+use crate::types::Sample;
+
+/// Antenna pairs written by the exporter, as `(rx, tx)`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Pairs {
+    All,
+    Only(Vec<(usize, usize)>),
+}
+
+impl FromStr for Pairs {
+    type Err = String;
+
+    /// `all`, or a comma separated list of `rx:tx` pairs, e.g. `0:0,1:1`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Pairs::All);
+        }
+
+        s.split(',')
+            .map(|pair| {
+                let mut it = pair.trim().splitn(2, ':');
+                let rx = it.next().and_then(|v| v.parse().ok());
+                let tx = it.next().and_then(|v| v.parse().ok());
+                match (rx, tx) {
+                    (Some(rx), Some(tx)) => Ok((rx, tx)),
+                    _ => Err(format!("invalid antenna pair '{}', expected rx:tx", pair)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Pairs::Only)
+    }
+}
+
+/// Per-tone values written for every antenna pair
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Values {
+    Amplitude,
+    Phase,
+    Both,
+}
+
+impl Values {
+    fn amplitude(self) -> bool {
+        self != Values::Phase
+    }
+
+    fn phase(self) -> bool {
+        self != Values::Amplitude
+    }
+}
+
+impl FromStr for Values {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "amplitude" => Ok(Values::Amplitude),
+            "phase" => Ok(Values::Phase),
+            "both" => Ok(Values::Both),
+            _ => Err(format!("invalid values '{}', expected amplitude, phase or both", s)),
+        }
+    }
+}
+
+/// Per-row metadata taken from the frame header
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Meta {
+    Tstamp,
+    Channel,
+    ChanBw,
+    Rate,
+    Nr,
+    Nc,
+    NumTones,
+    Noise,
+    Phyerr,
+    Rssi,
+    Rssi0,
+    Rssi1,
+    Rssi2,
+    PayloadLen,
+    CsiLen,
+}
+
+impl Meta {
+    pub const ALL: [Meta; 15] = [
+        Meta::Tstamp, Meta::Channel, Meta::ChanBw, Meta::Rate,
+        Meta::Nr, Meta::Nc, Meta::NumTones, Meta::Noise, Meta::Phyerr,
+        Meta::Rssi, Meta::Rssi0, Meta::Rssi1, Meta::Rssi2,
+        Meta::PayloadLen, Meta::CsiLen,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Meta::Tstamp => "tstamp",
+            Meta::Channel => "channel",
+            Meta::ChanBw => "chan_bw",
+            Meta::Rate => "rate",
+            Meta::Nr => "nr",
+            Meta::Nc => "nc",
+            Meta::NumTones => "num_tones",
+            Meta::Noise => "noise",
+            Meta::Phyerr => "phyerr",
+            Meta::Rssi => "rssi",
+            Meta::Rssi0 => "rssi_0",
+            Meta::Rssi1 => "rssi_1",
+            Meta::Rssi2 => "rssi_2",
+            Meta::PayloadLen => "payload_len",
+            Meta::CsiLen => "csi_len",
+        }
+    }
+
+    pub fn value(self, st: &CSIStruct) -> String {
+        match self {
+            Meta::Tstamp => st.tstamp.to_string(),
+            Meta::Channel => st.channel.to_string(),
+            Meta::ChanBw => st.chanBW.to_string(),
+            Meta::Rate => st.rate.to_string(),
+            Meta::Nr => st.nr.to_string(),
+            Meta::Nc => st.nc.to_string(),
+            Meta::NumTones => st.num_tones.to_string(),
+            Meta::Noise => st.noise.to_string(),
+            Meta::Phyerr => st.phyerr.to_string(),
+            Meta::Rssi => st.rssi.to_string(),
+            Meta::Rssi0 => st.rssi_0.to_string(),
+            Meta::Rssi1 => st.rssi_1.to_string(),
+            Meta::Rssi2 => st.rssi_2.to_string(),
+            Meta::PayloadLen => st.payload_len.to_string(),
+            Meta::CsiLen => st.csi_len.to_string(),
+        }
+    }
+}
+
+/// `all`, `none` or a comma separated list of metadata column names
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetaSet(pub Vec<Meta>);
+
+impl FromStr for MetaSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(MetaSet(Meta::ALL.to_vec())),
+            "none" => Ok(MetaSet(vec![])),
+            _ => s.split(',')
+                .map(|name| {
+                    let name = name.trim();
+                    Meta::ALL.iter()
+                        .copied()
+                        .find(|m| m.name() == name)
+                        .ok_or_else(|| format!("unknown metadata column '{}'", name))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(MetaSet),
+        }
+    }
+}
+
+/// Which columns the CSV exporter writes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CsvConfig {
+    pub pairs: Pairs,
+    pub values: Values,
+    pub meta: Vec<Meta>,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        Self {
+            pairs: Pairs::All,
+            values: Values::Amplitude,
+            meta: Meta::ALL.to_vec(),
+        }
+    }
+}
+
+/// Columns of one CSV file.
+///
+/// `Pairs::All` and the number of tones are resolved from the first sample
+/// of the file, so every row has the same width. Pairs or tones a later
+/// sample does not have are left empty.
+pub struct Layout {
+    pairs: Vec<(usize, usize)>,
+    tones: usize,
+    values: Values,
+    meta: Vec<Meta>,
+}
+
+impl Layout {
+    pub fn new(cfg: &CsvConfig, first: &Sample) -> Self {
+        let (nr, nc, tones) = first.dims();
+        let pairs = match &cfg.pairs {
+            Pairs::All => (0..nr)
+                .flat_map(|rx| (0..nc).map(move |tx| (rx, tx)))
+                .collect(),
+            Pairs::Only(pairs) => pairs.clone(),
+        };
+
+        Self {
+            pairs,
+            tones,
+            values: cfg.values,
+            meta: cfg.meta.clone(),
+        }
+    }
+
+    pub fn header(&self) -> Vec<String> {
+        let mut res: Vec<String> = vec!["date".into(), "device".into(), "x".into(), "y".into()];
+        res.extend(self.meta.iter().map(|m| m.name().to_string()));
+
+        for &(kind, enabled) in &[("amp", self.values.amplitude()), ("phase", self.values.phase())] {
+            if !enabled {
+                continue;
+            }
+            for (rx, tx) in &self.pairs {
+                res.extend((0..self.tones).map(|k| format!("{}_{}_{}_{}", kind, rx, tx, k)));
+            }
+        }
+
+        res
+    }
+
+    pub fn record(&self, r: &Sample) -> Vec<String> {
+        let mut res = vec![
+            format!("{}", r.date),
+            r.device.clone(),
+            format!("{}", r.x),
+            format!("{}", r.y),
+        ];
+        res.extend(self.meta.iter().map(|m| m.value(&r.status)));
+
+        let kinds: [(fn(ComplexDef<isize>) -> f64, bool); 2] = [
+            (abs, self.values.amplitude()),
+            (phase, self.values.phase()),
+        ];
+        for &(f, enabled) in &kinds {
+            if !enabled {
+                continue;
+            }
+            for &(rx, tx) in &self.pairs {
+                res.extend((0..self.tones).map(|k| {
+                    r.get(rx, tx, k)
+                        .map_or_else(String::new, |c| f(c).to_string())
+                }));
+            }
+        }
+
+        res
+    }
+}
+
+/// Columns appended to exports of samples with ground truth
+const TRUTH_COLUMNS: [&str; 7] = ["activity", "subject", "pose_x", "pose_y", "pose_z", "heading", "tags"];
+
+/// Values of `TRUTH_COLUMNS`; tags are a JSON object
+fn truth_record(truth: &Truth) -> Vec<String> {
+    let number = |v: Option<f64>| v.map_or_else(String::new, |v| v.to_string());
+    let (activity, subject, tags) = match truth.annotation {
+        Some(a) if a.tags.is_empty() => (a.activity.clone(), a.subject.clone(), None),
+        Some(a) => (a.activity.clone(), a.subject.clone(), serde_json::to_string(&a.tags).ok()),
+        None => (None, None, None),
+    };
+
+    let mut res = vec![activity.unwrap_or_default(), subject.unwrap_or_default()];
+    res.extend(truth.pose.iter().map(|v| number(*v)));
+    res.push(tags.unwrap_or_default());
+    res
+}
+
+/// Write samples as a single CSV table with a header row.
+///
+/// If any sample has an annotation or a pose, every row ends with the
+/// `TRUTH_COLUMNS`, empty for samples without one.
+pub fn write<W: Write>(cfg: &CsvConfig, samples: &[Sample], truth: &[Truth], w: W) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(w);
+    let annotated = truth.iter().any(|t| !t.is_empty());
+
+    if let Some(first) = samples.first() {
+        let layout = Layout::new(cfg, first);
+        let mut header = layout.header();
+        if annotated {
+            header.extend(TRUTH_COLUMNS.iter().map(|c| c.to_string()));
+        }
+        wtr.write_record(header)?;
+
+        for (i, r) in samples.iter().enumerate() {
+            let mut record = layout.record(r);
+            if annotated {
+                record.extend(truth_record(&truth[i]));
+            }
+            wtr.write_record(record)?;
+        }
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::io::{self, Write};
+
+use csi_types::mat::{frame_struct, write_mat, MatValue};
+
+use super::Truth;
+use crate::types::{Annotation, Sample};
+
+/// An annotation as a struct; missing values are empty strings or NaN and
+/// the tags a JSON object
+fn annotation_struct(a: &Annotation) -> MatValue {
+    let text = |v: &Option<String>| MatValue::Char(v.clone().unwrap_or_default());
+    let number = |v: Option<f64>| MatValue::scalar(v.unwrap_or(f64::NAN));
+
+    MatValue::record(vec![
+        ("id", MatValue::scalar(a.id as f64)),
+        ("start", MatValue::Char(a.start.to_rfc3339())),
+        ("end", MatValue::Char(a.end.map(|d| d.to_rfc3339()).unwrap_or_default())),
+        ("device", text(&a.device)),
+        ("session", text(&a.session)),
+        ("x", number(a.x)),
+        ("y", number(a.y)),
+        ("z", number(a.z)),
+        ("heading", number(a.heading)),
+        ("activity", text(&a.activity)),
+        ("subject", text(&a.subject)),
+        ("tags", MatValue::Char(serde_json::to_string(&a.tags).unwrap_or_default())),
+    ])
+}
+
+/// Write samples as a Level-5 `.mat` file.
+///
+/// `csi_trace` is a cell array of per-frame structs, laid out exactly like
+/// the result of the Atheros CSI Tool's `read_log_file.m`, so existing
+/// scripts can use `load('session.mat')` in its place. `xy` holds the
+/// recorded position of every frame as an `n x 2` matrix.
+///
+/// `annotation` is the 1-based index of every frame's entry in the
+/// `annotations` cell array, 0 for none, and `pose` the x, y, z and heading
+/// of the ground truth as an `n x 4` matrix.
+pub fn write<W: Write>(samples: &[Sample], truth: &[Truth], w: W) -> io::Result<()> {
+    let trace = MatValue::cell_column(
+        samples.iter()
+            .map(|s| frame_struct(&s.status, &s.csi, &s.payload))
+            .collect()
+    );
+
+    let xy = MatValue::Double {
+        dims: vec![samples.len(), 2],
+        re: samples.iter().map(|s| s.x)
+            .chain(samples.iter().map(|s| s.y))
+            .collect(),
+        im: None,
+    };
+
+    let (annotations, index) = super::distinct(truth);
+    let annotation = MatValue::column(index.iter().map(|i| i.map_or(0.0, |i| (i + 1) as f64)).collect());
+    // column-major
+    let pose = MatValue::Double {
+        dims: vec![samples.len(), 4],
+        re: (0..4)
+            .flat_map(|i| truth.iter().map(move |t| t.pose[i].unwrap_or(f64::NAN)))
+            .collect(),
+        im: None,
+    };
+    let annotations = MatValue::cell_column(annotations.into_iter().map(annotation_struct).collect());
+
+    write_mat(w, &[
+        ("csi_trace", &trace),
+        ("xy", &xy),
+        ("annotation", &annotation),
+        ("pose", &pose),
+        ("annotations", &annotations),
+    ])
+}
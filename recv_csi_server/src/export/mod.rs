@@ -0,0 +1,152 @@
+//! File formats collected samples can be exported to
+
+pub mod csv;
+pub mod mat;
+pub mod npz;
+#[cfg(feature = "columnar")]
+pub mod parquet;
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::trajectory::{Pose, Trajectory};
+use crate::types::{Annotation, Sample};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Npz,
+    Mat,
+    #[cfg(feature = "columnar")]
+    Parquet,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Npz => "npz",
+            Format::Mat => "mat",
+            #[cfg(feature = "columnar")]
+            Format::Parquet => "parquet",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Npz | Format::Mat => "application/octet-stream",
+            #[cfg(feature = "columnar")]
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "npz" => Ok(Format::Npz),
+            "mat" => Ok(Format::Mat),
+            #[cfg(feature = "columnar")]
+            "parquet" => Ok(Format::Parquet),
+            #[cfg(not(feature = "columnar"))]
+            "parquet" => Err("parquet export needs the `columnar` feature".to_string()),
+            _ => Err(format!("unknown export format '{}'", s)),
+        }
+    }
+}
+
+/// Format specific export settings
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub csv: csv::CsvConfig,
+    pub dtype: npz::Dtype,
+}
+
+/// Ground truth joined onto a sample
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Truth<'a> {
+    /// Of the annotations covering the sample, the one that started last
+    pub annotation: Option<&'a Annotation>,
+    /// x, y, z and heading: interpolated from the session's trajectory if
+    /// it covers the sample, or else those of the annotation, never a mix
+    pub pose: Pose,
+}
+
+impl Truth<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.annotation.is_none() && self.pose.iter().all(Option::is_none)
+    }
+}
+
+/// The ground truth of every sample from the annotations and the
+/// trajectories of the samples' sessions
+pub fn join<'a>(annotations: &'a [Annotation], trajectories: &BTreeMap<String, Trajectory>, samples: &[Sample]) -> Vec<Truth<'a>> {
+    samples.iter()
+        .map(|s| {
+            let annotation = annotations.iter()
+                .filter(|a| a.covers(s))
+                .max_by_key(|a| (a.start, a.id));
+            let pose = s.session.as_ref()
+                .and_then(|name| trajectories.get(name))
+                .and_then(|t| t.at(s.date).ok())
+                .unwrap_or_else(|| annotation.map_or([None; 4], |a| [a.x, a.y, a.z, a.heading]));
+
+            Truth { annotation, pose }
+        })
+        .collect()
+}
+
+/// The annotations of `truth`, each once, and the index of every sample's
+/// annotation among them
+pub fn distinct<'a>(truth: &[Truth<'a>]) -> (Vec<&'a Annotation>, Vec<Option<usize>>) {
+    let mut used: Vec<&Annotation> = vec![];
+    let index = truth.iter()
+        .map(|t| t.annotation.map(|a| match used.iter().position(|u| u.id == a.id) {
+            Some(i) => i,
+            None => {
+                used.push(a);
+                used.len() - 1
+            }
+        }))
+        .collect();
+
+    (used, index)
+}
+
+/// Matching samples with the annotations and trajectories `join` needs,
+/// taken out of a storage so they can be written without it
+pub struct Snapshot {
+    pub samples: Vec<Sample>,
+    pub annotations: Vec<Annotation>,
+    pub trajectories: BTreeMap<String, Trajectory>,
+}
+
+impl Snapshot {
+    /// Write the samples with their ground truth, returns how many were written
+    pub fn write<W: Write + Send>(&self, format: Format, opts: &Options, w: W) -> io::Result<usize> {
+        let truth = join(&self.annotations, &self.trajectories, &self.samples);
+        write(format, opts, &self.samples, &truth, w)?;
+
+        Ok(self.samples.len())
+    }
+}
+
+/// Write samples with their ground truth, see `join`
+pub fn write<W: Write + Send>(format: Format, opts: &Options, samples: &[Sample], truth: &[Truth], w: W) -> io::Result<()> {
+    match format {
+        Format::Csv => csv::write(&opts.csv, samples, truth, w)?,
+        Format::Npz => {
+            npz::write(samples, truth, opts.dtype, w)?;
+        }
+        Format::Mat => mat::write(samples, truth, w)?,
+        #[cfg(feature = "columnar")]
+        Format::Parquet => parquet::write(samples, truth, w)?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use csi_types::npy::{DateTime64Us, NpzWriter};
+use csi_types::ser::{abs, ComplexDef};
+use serde::{Deserialize, Serialize};
+
+use super::Truth;
+use crate::types::Sample;
+
+/// Element type of the `csi` array
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    /// Complex CSI
+    Complex64,
+    /// Amplitude only
+    Float32,
+    /// Amplitude only
+    Float64,
+}
+
+impl Dtype {
+    pub fn name(self) -> &'static str {
+        match self {
+            Dtype::Complex64 => "complex64",
+            Dtype::Float32 => "float32",
+            Dtype::Float64 => "float64",
+        }
+    }
+}
+
+impl Default for Dtype {
+    fn default() -> Self {
+        Dtype::Complex64
+    }
+}
+
+impl FromStr for Dtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "complex64" => Ok(Dtype::Complex64),
+            "float32" => Ok(Dtype::Float32),
+            "float64" => Ok(Dtype::Float64),
+            _ => Err(format!("invalid dtype '{}', expected complex64, float32 or float64", s)),
+        }
+    }
+}
+
+/// Write samples as a `.npz` archive.
+///
+/// `csi` has shape `(samples, nr, nc, tones)` using the largest dimensions
+/// of the exported frames; smaller frames are zero padded and their actual
+/// size is kept in the `nr`, `nc` and `num_tones` arrays.
+///
+/// `annotation` indexes the `annotations` list of `metadata.json`, -1 for
+/// samples without one; `pose` holds the x, y, z and heading of the
+/// ground truth, NaN where unknown.
+pub fn write<W: Write>(samples: &[Sample], truth: &[Truth], dtype: Dtype, w: W) -> io::Result<W> {
+    let n = samples.len();
+    let (nr, nc, tones) = samples.iter()
+        .map(Sample::dims)
+        .fold((0, 0, 0), |(a, b, c), (x, y, z)| (a.max(x), b.max(y), c.max(z)));
+    let shape = [n, nr, nc, tones];
+
+    // zero padded, row-major (sample, rx, tx, tone)
+    let cell = |s: &Sample, i: usize| {
+        let (rx, tx, k) = (i / (nc * tones), i / tones % nc, i % tones);
+        s.get(rx, tx, k).unwrap_or(ComplexDef { re: 0, im: 0 })
+    };
+    let cells = nr * nc * tones;
+    let flat = || samples.iter().flat_map(move |s| (0..cells).map(move |i| cell(s, i)));
+
+    let mut npz = NpzWriter::new(w);
+
+    match dtype {
+        Dtype::Complex64 => {
+            let data: Vec<_> = flat()
+                .map(|c| ComplexDef { re: c.re as f32, im: c.im as f32 })
+                .collect();
+            npz.add_array("csi", &shape, &data)?;
+        }
+        Dtype::Float32 => {
+            let data: Vec<f32> = flat().map(|c| abs(c) as f32).collect();
+            npz.add_array("csi", &shape, &data)?;
+        }
+        Dtype::Float64 => {
+            let data: Vec<f64> = flat().map(abs).collect();
+            npz.add_array("csi", &shape, &data)?;
+        }
+    }
+
+    let devices: Vec<&str> = samples.iter()
+        .map(|s| s.device.as_str())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let device_idx: Vec<u16> = samples.iter()
+        .map(|s| devices.iter().position(|d| *d == s.device).unwrap_or(0) as u16)
+        .collect();
+
+    let timestamp: Vec<_> = samples.iter()
+        .map(|s| DateTime64Us(s.date.timestamp() * 1_000_000 + s.date.timestamp_subsec_micros() as i64))
+        .collect();
+    npz.add_array("timestamp", &[n], &timestamp)?;
+    npz.add_array("tstamp", &[n], &samples.iter().map(|s| s.status.tstamp).collect::<Vec<_>>())?;
+    npz.add_array("device", &[n], &device_idx)?;
+    npz.add_array("rssi", &[n], &samples.iter().map(|s| s.status.rssi).collect::<Vec<_>>())?;
+    npz.add_array(
+        "rssi_chain",
+        &[n, 3],
+        &samples.iter()
+            .flat_map(|s| vec![s.status.rssi_0, s.status.rssi_1, s.status.rssi_2])
+            .collect::<Vec<_>>(),
+    )?;
+    npz.add_array("noise", &[n], &samples.iter().map(|s| s.status.noise).collect::<Vec<_>>())?;
+    npz.add_array("x", &[n], &samples.iter().map(|s| s.x).collect::<Vec<_>>())?;
+    npz.add_array("y", &[n], &samples.iter().map(|s| s.y).collect::<Vec<_>>())?;
+    npz.add_array("nr", &[n], &samples.iter().map(|s| s.dims().0 as u8).collect::<Vec<_>>())?;
+    npz.add_array("nc", &[n], &samples.iter().map(|s| s.dims().1 as u8).collect::<Vec<_>>())?;
+    npz.add_array("num_tones", &[n], &samples.iter().map(|s| s.dims().2 as u16).collect::<Vec<_>>())?;
+
+    let (annotations, index) = super::distinct(truth);
+    npz.add_array("annotation", &[n], &index.iter().map(|i| i.map_or(-1, |i| i as i32)).collect::<Vec<_>>())?;
+    npz.add_array(
+        "pose",
+        &[n, 4],
+        &truth.iter()
+            .flat_map(|t| t.pose.iter().map(|v| v.unwrap_or(f64::NAN)))
+            .collect::<Vec<_>>(),
+    )?;
+
+    let channels: BTreeSet<u16> = samples.iter().map(|s| s.status.channel).collect();
+    let metadata = serde_json::json!({
+        "generator": "recv_csi_server",
+        "created": Utc::now(),
+        "samples": n,
+        "shape": shape,
+        "dtype": dtype.name(),
+        "devices": devices,
+        "channels": channels,
+        "first": samples.first().map(|s| s.date),
+        "last": samples.last().map(|s| s.date),
+        "annotations": annotations,
+    });
+    npz.add_file("metadata.json", metadata.to_string().as_bytes())?;
+
+    npz.finish()
+}
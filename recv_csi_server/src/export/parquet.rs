@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayRef, FixedSizeListArray, Float64Array, Int16Array, StringArray,
+    TimestampMicrosecondArray, UInt16Array, UInt64Array, UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use chrono::prelude::*;
+use csi_types::ser::ComplexDef;
+use csi_types::CSIStruct;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::common::file_timestamp;
+use crate::device::sanitize;
+use super::Truth;
+use crate::types::{Annotation, Sample};
+
+/// Largest CSI matrix the Atheros driver reports: 3x3 antennas, 114 tones
+pub const MAX_NR: usize = 3;
+pub const MAX_NC: usize = 3;
+pub const MAX_TONES: usize = 114;
+const CSI_CELLS: usize = MAX_NR * MAX_NC * MAX_TONES;
+
+fn to_io<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn csi_item() -> Arc<Field> {
+    Arc::new(Field::new("item", DataType::Int16, false))
+}
+
+/// Schema shared by every file, so a dataset can be read as one table.
+///
+/// `csi_re` and `csi_im` always hold `3 x 3 x 114` values in row-major
+/// `(rx, tx, tone)` order; cells outside of the frame's `nr`, `nc` and
+/// `num_tones` are zero.
+pub fn schema() -> SchemaRef {
+    let u8_field = |name| Field::new(name, DataType::UInt8, false);
+    let csi = DataType::FixedSizeList(csi_item(), CSI_CELLS as i32);
+
+    Arc::new(Schema::new(vec![
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("device_id", DataType::Utf8, false),
+        Field::new("x", DataType::Float64, false),
+        Field::new("y", DataType::Float64, false),
+        Field::new("tstamp", DataType::UInt64, false),
+        Field::new("channel", DataType::UInt16, false),
+        u8_field("chan_bw"),
+        u8_field("rate"),
+        u8_field("nr"),
+        u8_field("nc"),
+        u8_field("num_tones"),
+        u8_field("noise"),
+        u8_field("phyerr"),
+        u8_field("rssi"),
+        u8_field("rssi_0"),
+        u8_field("rssi_1"),
+        u8_field("rssi_2"),
+        Field::new("payload_len", DataType::UInt16, false),
+        Field::new("csi_len", DataType::UInt16, false),
+        Field::new("csi_re", csi.clone(), false),
+        Field::new("csi_im", csi, false),
+    ]))
+}
+
+/// Convert samples to a record batch with the schema above
+pub fn batch(samples: &[Sample]) -> io::Result<RecordBatch> {
+    let status = |f: fn(&CSIStruct) -> u8| -> ArrayRef {
+        Arc::new(UInt8Array::from(samples.iter().map(|s| f(&s.status)).collect::<Vec<_>>()))
+    };
+
+    let mut re = Vec::with_capacity(samples.len() * CSI_CELLS);
+    let mut im = Vec::with_capacity(samples.len() * CSI_CELLS);
+    for s in samples {
+        let cell = |v: isize| i16::try_from(v).map_err(|_| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("CSI value {} of {} at {} does not fit in 16 bits", v, s.device, s.date),
+        ));
+        for i in 0..CSI_CELLS {
+            let (rx, tx, k) = (i / (MAX_NC * MAX_TONES), i / MAX_TONES % MAX_NC, i % MAX_TONES);
+            let c = s.get(rx, tx, k).unwrap_or(ComplexDef { re: 0, im: 0 });
+            re.push(cell(c.re)?);
+            im.push(cell(c.im)?);
+        }
+    }
+    let csi = |values: Vec<i16>| -> io::Result<ArrayRef> {
+        let list = FixedSizeListArray::try_new(csi_item(), CSI_CELLS as i32, Arc::new(Int16Array::from(values)), None)
+            .map_err(to_io)?;
+        Ok(Arc::new(list))
+    };
+
+    let timestamp = TimestampMicrosecondArray::from(
+        samples.iter()
+            .map(|s| s.date.timestamp() * 1_000_000 + s.date.timestamp_subsec_micros() as i64)
+            .collect::<Vec<_>>()
+    ).with_timezone("UTC");
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(timestamp),
+        Arc::new(StringArray::from(samples.iter().map(|s| s.device.as_str()).collect::<Vec<_>>())),
+        Arc::new(Float64Array::from(samples.iter().map(|s| s.x).collect::<Vec<_>>())),
+        Arc::new(Float64Array::from(samples.iter().map(|s| s.y).collect::<Vec<_>>())),
+        Arc::new(UInt64Array::from(samples.iter().map(|s| s.status.tstamp).collect::<Vec<_>>())),
+        Arc::new(UInt16Array::from(samples.iter().map(|s| s.status.channel).collect::<Vec<_>>())),
+        status(|s| s.chanBW),
+        status(|s| s.rate),
+        status(|s| s.nr),
+        status(|s| s.nc),
+        status(|s| s.num_tones),
+        status(|s| s.noise),
+        status(|s| s.phyerr),
+        status(|s| s.rssi),
+        status(|s| s.rssi_0),
+        status(|s| s.rssi_1),
+        status(|s| s.rssi_2),
+        Arc::new(UInt16Array::from(samples.iter().map(|s| s.status.payload_len).collect::<Vec<_>>())),
+        Arc::new(UInt16Array::from(samples.iter().map(|s| s.status.csi_len).collect::<Vec<_>>())),
+        csi(re)?,
+        csi(im)?,
+    ];
+
+    RecordBatch::try_new(schema(), columns).map_err(to_io)
+}
+
+/// `schema()` with the nullable ground-truth columns of single-file exports
+pub fn annotated_schema() -> SchemaRef {
+    let mut fields: Vec<Field> = schema().fields().iter().map(|f| f.as_ref().clone()).collect();
+    fields.extend(vec![
+        Field::new("activity", DataType::Utf8, true),
+        Field::new("subject", DataType::Utf8, true),
+        Field::new("pose_x", DataType::Float64, true),
+        Field::new("pose_y", DataType::Float64, true),
+        Field::new("pose_z", DataType::Float64, true),
+        Field::new("heading", DataType::Float64, true),
+        // JSON object
+        Field::new("tags", DataType::Utf8, true),
+    ]);
+
+    Arc::new(Schema::new(fields))
+}
+
+/// `batch` with every sample's ground truth
+pub fn annotated_batch(samples: &[Sample], truth: &[Truth]) -> io::Result<RecordBatch> {
+    let text = |f: fn(&Annotation) -> Option<&str>| -> ArrayRef {
+        Arc::new(StringArray::from(truth.iter().map(|t| t.annotation.and_then(f)).collect::<Vec<_>>()))
+    };
+    let pose = |i: usize| -> ArrayRef {
+        Arc::new(Float64Array::from(truth.iter().map(|t| t.pose[i]).collect::<Vec<_>>()))
+    };
+    let tags: Vec<Option<String>> = truth.iter()
+        .map(|t| t.annotation.map(|a| serde_json::to_string(&a.tags)).transpose())
+        .collect::<Result<_, _>>()
+        .map_err(to_io)?;
+
+    let mut columns = batch(samples)?.columns().to_vec();
+    columns.extend(vec![
+        text(|a| a.activity.as_deref()),
+        text(|a| a.subject.as_deref()),
+        pose(0),
+        pose(1),
+        pose(2),
+        pose(3),
+        Arc::new(StringArray::from(tags)) as ArrayRef,
+    ]);
+
+    RecordBatch::try_new(annotated_schema(), columns).map_err(to_io)
+}
+
+fn properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build()
+}
+
+/// Write samples as a single Parquet file, with their ground truth
+pub fn write<W: Write + Send>(samples: &[Sample], truth: &[Truth], w: W) -> io::Result<()> {
+    let mut writer = ArrowWriter::try_new(w, annotated_schema(), Some(properties())).map_err(to_io)?;
+    writer.write(&annotated_batch(samples, truth)?).map_err(to_io)?;
+    writer.close().map_err(to_io)?;
+
+    Ok(())
+}
+
+/// Start of the hour a sample belongs to
+fn hour_of(date: DateTime<Utc>) -> DateTime<Utc> {
+    date.with_nanosecond(0)
+        .and_then(|d| d.with_second(0))
+        .and_then(|d| d.with_minute(0))
+        .unwrap_or(date)
+}
+
+/// Hive-style directory of a device's hour: `device=<id>/date=<day>/hour=<HH>`
+pub fn partition_dir(device: &str, hour: DateTime<Utc>) -> PathBuf {
+    PathBuf::from(format!("device={}", sanitize(device)))
+        .join(format!("date={}", hour.format("%Y-%m-%d")))
+        .join(format!("hour={}", hour.format("%H")))
+}
+
+/// An open part file of one device and hour
+struct Part {
+    path: PathBuf,
+    hour: DateTime<Utc>,
+    writer: ArrowWriter<File>,
+    pending: Vec<Sample>,
+}
+
+impl Part {
+    /// Write buffered samples as a row group
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.writer.write(&batch(&self.pending)?).map_err(to_io)?;
+        self.writer.flush().map_err(to_io)?;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    fn close(mut self) -> io::Result<PathBuf> {
+        self.flush()?;
+        self.writer.close().map_err(to_io)?;
+
+        Ok(self.path)
+    }
+}
+
+/// Parquet dataset partitioned by device and hour.
+///
+/// Each device has one open part file for the current hour; samples are
+/// buffered and written in row groups of `row_group` samples. A part file
+/// is only readable once it is closed, which happens when the hour changes
+/// and on `close`.
+pub struct Dataset {
+    dir: PathBuf,
+    row_group: usize,
+    parts: BTreeMap<String, Part>,
+}
+
+impl Dataset {
+    pub fn new<P: AsRef<Path>>(dir: P, row_group: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            row_group: row_group.max(1),
+            parts: BTreeMap::new(),
+        })
+    }
+
+    fn open(&self, device: &str, hour: DateTime<Utc>) -> io::Result<Part> {
+        let dir = self.dir.join(partition_dir(device, hour));
+        fs::create_dir_all(&dir)?;
+
+        let base = format!("part-{}", file_timestamp(Utc::now()));
+        let mut n = 0;
+        loop {
+            let name = if n == 0 {
+                format!("{}.parquet", base)
+            } else {
+                format!("{}-{}.parquet", base, n)
+            };
+            let path = dir.join(name);
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let writer = ArrowWriter::try_new(file, schema(), Some(properties())).map_err(to_io)?;
+                    return Ok(Part {
+                        path,
+                        hour,
+                        writer,
+                        pending: vec![],
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn write(&mut self, s: &Sample) -> io::Result<()> {
+        let hour = hour_of(s.date);
+
+        if self.parts.get(&s.device).map_or(false, |p| p.hour != hour) {
+            let path = self.parts.remove(&s.device).unwrap().close()?;
+            println!("Done saving {}", path.display());
+        }
+
+        if !self.parts.contains_key(&s.device) {
+            let part = self.open(&s.device, hour)?;
+            self.parts.insert(s.device.clone(), part);
+        }
+
+        let part = self.parts.get_mut(&s.device).unwrap();
+        part.pending.push(s.clone());
+        if part.pending.len() >= self.row_group {
+            part.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write every buffered sample as a row group
+    pub fn flush(&mut self) -> io::Result<()> {
+        for part in self.parts.values_mut() {
+            part.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish every open part file
+    pub fn close(&mut self) -> io::Result<()> {
+        let parts = std::mem::replace(&mut self.parts, BTreeMap::new());
+        for (_, part) in parts {
+            let path = part.close()?;
+            println!("Done saving {}", path.display());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::Int16Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::testutil::{date, sample, temp_dir};
+
+    fn read(path: &Path) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("parquet_file");
+        let samples = vec![
+            sample("a", date("2020-06-01T10:00:00Z"), 511),
+            sample("b", date("2020-06-01T10:00:01Z"), -512),
+        ];
+        let path = dir.join("out.parquet");
+        write(&samples, &[Truth::default(), Truth::default()], File::create(&path).unwrap()).unwrap();
+
+        let batches = read(&path);
+        assert_eq!(batches.len(), 1);
+        let b = &batches[0];
+        assert_eq!(b.schema(), annotated_schema());
+        assert_eq!(b.num_rows(), 2);
+        assert_eq!(b.column_by_name("device_id").unwrap().as_string::<i32>().value(1), "b");
+        assert_eq!(b.column_by_name("rssi").unwrap().as_primitive::<arrow::datatypes::UInt8Type>().value(0), 40);
+        assert!(b.column_by_name("activity").unwrap().is_null(0));
+
+        // (rx, tx, tone) row-major, zero outside of the frame
+        let re = b.column_by_name("csi_re").unwrap().as_fixed_size_list();
+        let first = re.value(0);
+        let first = first.as_primitive::<Int16Type>();
+        assert_eq!(first.len(), CSI_CELLS);
+        assert_eq!(first.value(0), 511);
+        assert_eq!(first.value(MAX_NC * MAX_TONES + 2), 511);
+        assert_eq!(first.value(3), 0);
+        assert_eq!(first.value(MAX_TONES), 0);
+        let im = b.column_by_name("csi_im").unwrap().as_fixed_size_list().value(1);
+        assert_eq!(im.as_primitive::<Int16Type>().value(0), 512);
+
+        // values that do not fit are an error, not truncated
+        let err = batch(&[sample("a", date("2020-06-01T10:00:00Z"), 40_000)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dataset() {
+        let dir = temp_dir("parquet_dataset");
+        let mut dataset = Dataset::new(&dir, 2).unwrap();
+        for s in &[
+            sample("a", date("2020-06-01T10:59:58Z"), 1),
+            sample("b", date("2020-06-01T10:59:59Z"), 2),
+            sample("a", date("2020-06-01T10:59:59Z"), 3),
+            sample("a", date("2020-06-01T10:59:59.5Z"), 4),
+            sample("a", date("2020-06-01T11:00:00Z"), 5),
+        ] {
+            dataset.write(s).unwrap();
+        }
+        dataset.close().unwrap();
+
+        let files = |device: &str, hour: &str| -> Vec<PathBuf> {
+            let hour = hour.parse().unwrap();
+            fs::read_dir(dir.join(partition_dir(device, hour))).unwrap()
+                .map(|e| e.unwrap().path())
+                .collect()
+        };
+        let rows = |paths: Vec<PathBuf>| -> Vec<i16> {
+            paths.iter()
+                .map(PathBuf::as_path)
+                .flat_map(read)
+                .flat_map(|b| {
+                    let re = b.column_by_name("csi_re").unwrap().as_fixed_size_list().clone();
+                    (0..re.len()).map(move |i| re.value(i).as_primitive::<Int16Type>().value(0))
+                })
+                .collect()
+        };
+
+        assert!(dir.join("device=a/date=2020-06-01/hour=10").is_dir());
+        // two row groups of the first hour, then a new file for the next one
+        assert_eq!(rows(files("a", "2020-06-01T10:00:00Z")), vec![1, 3, 4]);
+        assert_eq!(rows(files("a", "2020-06-01T11:00:00Z")), vec![5]);
+        assert_eq!(rows(files("b", "2020-06-01T10:00:00Z")), vec![2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
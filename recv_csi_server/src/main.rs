@@ -1,52 +1,376 @@
 use actix_web::{
     error, middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer, // http::StatusCode,
 };
-use bytes::{// Bytes,
-            BytesMut};
-use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 
-const MAX_SIZE: usize = 262_144;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::SinkExt;
 
 use structopt::StructOpt;
 
 use chrono::prelude::*;
 
-use csi_types::{ser::SerCSI, ser::abs
-                // CSIStruct, CSI, ComplexDef
-};
+use csi_types::stats::FrameStats;
 
 mod types;
 use types::*;
 
 mod common;
-use common::*;
+use common::file_timestamp;
+
+mod codec;
+use codec::{decode_body, decode_csi, read_body, read_body_max};
+
+mod device;
+use device::device_id;
+
+mod store;
+use store::{Filter, Retention, SampleStore, Spill};
+
+mod storage;
+use storage::{Backend, Storage};
+
+mod query;
+
+mod stream;
+
+mod events;
+
+mod metrics;
+
+mod status;
+
+mod auth;
+use auth::Auth;
+
+mod output;
+use output::{Output, Rotation};
+
+mod export;
+use export::csv::{CsvConfig, MetaSet, Pairs, Values};
+use export::npz::Dtype;
+use export::Format as ExportFormat;
+
+mod shutdown;
+
+mod config;
+
+mod survey;
+use survey::Survey;
+
+mod trajectory;
+use trajectory::Trajectory;
+
+#[cfg(feature = "sqlite")]
+mod db;
+
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(test)]
+mod testutil;
 
 use std::sync::Mutex;
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Inter-arrival histogram of `/stats`: 40 bins of 0.5 ms
+const INTERVAL_BIN_US: u64 = 500;
+const INTERVAL_BINS: usize = 40;
+
+/// Defaults of the flags that may also come from `--config`
+const FLUSH_SECS: u64 = 5;
+const PARQUET_ROW_GROUP: usize = 10000;
+const DB_BATCH: usize = 500;
+const SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const SPILL_SEGMENT: usize = 10000;
+const SURVEY_MAX_BAD: f64 = 0.1;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "recv_csi_server", about = "Receive CSI data Server")]
 struct Opt {
-    /// Activate debug mode
+    /// Log every request and debug messages, unless RUST_LOG says otherwise
     // short and long flags (-d, --debug) will be deduced from the field's name
     #[structopt(short, long)]
     debug: bool,
 
+    /// TOML configuration file, flags override its values
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
     #[structopt(long)]
-    addr: String,
+    addr: Option<String>,
 
+    /// Start a new output file every N samples
     #[structopt(long)]
     write_at_least: Option<usize>,
 
+    /// Directory for the rolling CSV output, which is only written when
+    /// this or --write-at-least is given
+    #[structopt(long, parse(from_os_str))]
+    out_dir: Option<PathBuf>,
+
+    /// Start a new output file once the current one reaches this size
+    #[structopt(long)]
+    rotate_mb: Option<u64>,
+
+    /// Start a new output file after this many minutes
+    #[structopt(long)]
+    rotate_minutes: Option<u64>,
+
+    /// Flush buffered output to disk every N seconds [default: 5]
+    #[structopt(long)]
+    flush_secs: Option<u64>,
+
+    /// Sync output and spilled samples to disk every N seconds
+    #[structopt(long)]
+    checkpoint_secs: Option<u64>,
+
+    /// Antenna pairs written to CSV: `all` or a list of rx:tx, e.g. `0:0,1:1`
+    /// [default: all]
+    #[structopt(long)]
+    csv_pairs: Option<Pairs>,
+
+    /// Per-tone CSV columns: amplitude, phase or both [default: amplitude]
+    #[structopt(long)]
+    csv_values: Option<Values>,
+
+    /// Metadata CSV columns: all, none, or a list such as `tstamp,rssi,noise`
+    /// [default: all]
+    #[structopt(long)]
+    csv_meta: Option<MetaSet>,
+
+    /// Keep samples in memory only, do not write output files
+    #[structopt(long)]
+    no_output: bool,
+
+    /// Also write samples to a Parquet dataset partitioned by device and hour
+    /// in this directory (needs the `columnar` feature)
+    #[structopt(long, parse(from_os_str))]
+    parquet_dir: Option<PathBuf>,
+
+    /// Samples per Parquet row group [default: 10000]
+    #[structopt(long)]
+    parquet_row_group: Option<usize>,
+
+    /// Store samples, devices and positions in this SQLite database (needs
+    /// the `sqlite` feature). Range queries and exports read from it.
+    #[structopt(long, parse(from_os_str))]
+    db: Option<PathBuf>,
+
+    /// Samples inserted per database transaction [default: 500]
+    #[structopt(long)]
+    db_batch: Option<usize>,
+
+    /// Serve HTTPS with this PEM certificate chain (needs the `tls` feature)
+    #[structopt(long, parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[structopt(long, parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require this token (or HMAC key) from every device posting to /csi
+    /// and /post_xy
+    #[structopt(long)]
+    auth_token: Option<String>,
+
+    /// Per-device keys, one `<device> <key>` per line; `*` as the device
+    /// is the key of every device not listed
+    #[structopt(long, parse(from_os_str))]
+    auth_keys: Option<PathBuf>,
+
+    /// How devices present their key: token (Authorization: Bearer) or
+    /// hmac (signed requests) [default: token]
+    #[structopt(long)]
+    auth: Option<auth::Mode>,
+
+    /// Seconds to wait for in-flight requests on shutdown [default: 30]
+    #[structopt(long)]
+    shutdown_timeout: Option<u64>,
+
+    /// Start a session with this name, or resume it after a restart
+    #[structopt(long)]
+    session: Option<String>,
+
+    /// Someone is present during --session
     #[structopt(long, short = "p")]
     is_present: bool,
 
-    #[structopt(long, short)]
+    /// Fixed position of --session, instead of the positions posted to /post_xy
+    #[structopt(long, short, requires = "y")]
     x: Option<f64>,
 
-    #[structopt(long, short)]
+    #[structopt(long, short, requires = "x")]
     y: Option<f64>,
+
+    /// Survey the reference points in this file, one `x,y` per line:
+    /// collect samples at each point and wait for the operator to confirm
+    /// the next one (Enter, or POST /survey/next)
+    #[structopt(long, parse(from_os_str))]
+    survey: Option<PathBuf>,
+
+    /// A survey point is complete once every device heard in the survey sent
+    /// this many samples there
+    #[structopt(long)]
+    survey_samples: Option<usize>,
+
+    /// A survey point is complete after this many seconds
+    #[structopt(long)]
+    survey_secs: Option<u64>,
+
+    /// Flag survey points with fewer samples from a device
+    /// [default: --survey-samples, or 1]
+    #[structopt(long)]
+    survey_min_samples: Option<usize>,
+
+    /// Frames with a lower RSSI count as low quality in the survey
+    #[structopt(long)]
+    survey_min_rssi: Option<u8>,
+
+    /// Flag survey points where a device's share of low-quality and lost
+    /// frames is higher [default: 0.1]
+    #[structopt(long)]
+    survey_max_bad: Option<f64>,
+
+    /// Keep at most this many of the most recent samples in memory
+    #[structopt(long)]
+    keep_samples: Option<usize>,
+
+    /// Keep only the last T minutes of samples in memory
+    #[structopt(long)]
+    keep_minutes: Option<i64>,
+
+    /// Move samples evicted from memory to this directory instead of dropping them
+    #[structopt(long, parse(from_os_str))]
+    spill_dir: Option<PathBuf>,
+
+    /// Number of samples per spill segment file [default: 10000]
+    #[structopt(long)]
+    spill_segment: Option<usize>,
+
+    /// Storage backend: memory (default), file (every sample in
+    /// --spill-dir) or sqlite (--db, the default when --db is present)
+    #[structopt(long)]
+    storage: Option<Backend>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+impl Opt {
+    /// Take what was not given on the command line from the config file
+    fn apply(&mut self, file: config::Config) {
+        let config::Config { server, output, storage, retention, auth, session, survey } = file;
+
+        self.addr = self.addr.take().or(server.addr);
+        self.shutdown_timeout = self.shutdown_timeout.or(server.shutdown_timeout);
+        self.tls_cert = self.tls_cert.take().or(server.tls_cert);
+        self.tls_key = self.tls_key.take().or(server.tls_key);
+
+        self.no_output |= output.enabled == Some(false);
+        self.out_dir = self.out_dir.take().or(output.dir);
+        self.write_at_least = self.write_at_least.or(output.write_at_least);
+        self.rotate_mb = self.rotate_mb.or(output.rotate_mb);
+        self.rotate_minutes = self.rotate_minutes.or(output.rotate_minutes);
+        self.flush_secs = self.flush_secs.or(output.flush_secs);
+        self.checkpoint_secs = self.checkpoint_secs.or(output.checkpoint_secs);
+        self.csv_pairs = self.csv_pairs.take().or(output.csv.pairs);
+        self.csv_values = self.csv_values.or(output.csv.values);
+        self.csv_meta = self.csv_meta.take().or(output.csv.meta);
+        self.parquet_dir = self.parquet_dir.take().or(output.parquet_dir);
+        self.parquet_row_group = self.parquet_row_group.or(output.parquet_row_group);
+
+        self.storage = self.storage.or(storage.backend);
+        self.db = self.db.take().or(storage.db);
+        self.db_batch = self.db_batch.or(storage.db_batch);
+        self.spill_dir = self.spill_dir.take().or(storage.spill_dir);
+        self.spill_segment = self.spill_segment.or(storage.spill_segment);
+
+        self.keep_samples = self.keep_samples.or(retention.keep_samples);
+        self.keep_minutes = self.keep_minutes.or(retention.keep_minutes);
+
+        self.auth = self.auth.or(auth.mode);
+        self.auth_token = self.auth_token.take().or(auth.token);
+        self.auth_keys = self.auth_keys.take().or(auth.keys);
+
+        self.session = self.session.take().or(session.name);
+        self.is_present |= session.present == Some(true);
+        self.x = self.x.or(session.x);
+        self.y = self.y.or(session.y);
+
+        self.survey = self.survey.take().or(survey.points);
+        self.survey_samples = self.survey_samples.or(survey.samples);
+        self.survey_secs = self.survey_secs.or(survey.secs);
+        self.survey_min_samples = self.survey_min_samples.or(survey.min_samples);
+        self.survey_min_rssi = self.survey_min_rssi.or(survey.min_rssi);
+        self.survey_max_bad = self.survey_max_bad.or(survey.max_bad);
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Export spilled samples to a file instead of running the server
+    Export(ExportOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+    /// Spill directory of a previous run
+    #[structopt(long, parse(from_os_str))]
+    spill_dir: Option<PathBuf>,
+
+    /// SQLite database of a previous run
+    #[structopt(long, parse(from_os_str), conflicts_with = "spill-dir")]
+    db: Option<PathBuf>,
+
+    /// Output file
+    #[structopt(long, parse(from_os_str))]
+    out: PathBuf,
+
+    /// csv, npz, mat or parquet, taken from the output file extension if not present
+    #[structopt(long)]
+    format: Option<ExportFormat>,
+
+    /// Write a Parquet dataset partitioned by device and hour into the
+    /// `--out` directory instead of a single file
+    #[structopt(long)]
+    partition: bool,
+
+    /// Element type of the npz `csi` array: complex64, float32 or float64
+    #[structopt(long, default_value = "complex64")]
+    dtype: Dtype,
+
+    #[structopt(long)]
+    device: Option<String>,
+
+    #[structopt(long)]
+    session: Option<String>,
+
+    #[structopt(long)]
+    from: Option<DateTime<Utc>>,
+
+    #[structopt(long)]
+    to: Option<DateTime<Utc>>,
+
+    /// Columns of CSV exports, see `ExportOpt::apply`
+    #[structopt(skip)]
+    csv: CsvConfig,
+}
+
+impl ExportOpt {
+    /// Take what the subcommand does not set from the server's flags and
+    /// `--config`: the storage of the run and the CSV columns
+    fn apply(&mut self, opt: &Opt) {
+        if self.spill_dir.is_none() && self.db.is_none() {
+            self.spill_dir = opt.spill_dir.clone();
+            self.db = opt.db.clone();
+        }
+        self.csv = csv_config(opt);
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -55,134 +379,986 @@ struct XYData {
     y: f64,
 }
 
-async fn post_csi(mut payload: web::Payload, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
-    let mut body = BytesMut::new();
-    let x = &mut *shared_state.lock().unwrap();
-    
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+/// Optional `?device=` query parameter of the read endpoints
+#[derive(Clone, Debug, Deserialize)]
+struct DeviceFilter {
+    device: Option<String>,
+    #[serde(default)]
+    values: View,
+}
+
+/// Query parameters of the legacy `/get`.
+///
+/// `values` selects amplitude (default), phase or the complex CSI. The newest
+/// `limit` samples are returned, `/query` pages through the rest.
+#[derive(Clone, Debug, Deserialize)]
+struct RangeFilter {
+    device: Option<String>,
+    session: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    #[serde(default)]
+    values: View,
+}
+
+async fn post_csi(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let device = device_id(&req);
+    let body = decode_csi(&req, payload, &auth, &device).await?;
+    // a ragged matrix would panic in every reader of the sample
+    body.validate().map_err(|e| {
+        metrics::DECODE_FAILURES.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        error::ErrorBadRequest(e)
+    })?;
+    let x = &mut *metrics::lock(&shared_state);
+
+    if !x.accepting {
+        return Err(error::ErrorServiceUnavailable("shutting down"));
+    }
+
+    let (px, py) = x.position();
+    let now = Utc::now();
+
+    let sample = Sample {
+        date: now,
+        device: device.clone(),
+        x: px,
+        y: py,
+        csi: body.csi_matrix,
+        status: body.csi_status,
+        payload: body.payload,
+        session: x.session.as_ref().map(|s| s.name.clone()),
+    };
+
+    x.signal.entry(device.clone()).or_default().update(&sample.status);
+    x.timing.entry(device.clone())
+        .or_insert_with(|| FrameStats::new(INTERVAL_BIN_US, INTERVAL_BINS))
+        .push(sample.status.tstamp, &sample.payload);
+    if let Some(survey) = x.survey.as_mut() {
+        if let Some(point) = survey.record(&sample) {
+            survey.announce(point);
         }
-        body.extend_from_slice(&chunk);
     }
+    x.push(sample)?;
 
-    let body: SerCSI = bincode::deserialize(&body)
-        .unwrap();
+    let stats = x.devices.entry(device.clone())
+        .or_insert_with(|| DeviceStats::new(now));
+    stats.frames += 1;
+    stats.last_seen = now;
 
-    let m = body.csi_matrix.clone();
-    let mm: Vec<Vec<Vec<f64>>> = m.iter().map(
-        |a| a.iter().map(
-            |b| b.iter().map(
-                |x| abs(x.clone())
-            )
-            // there are almost always 56 channels in CSI data
-                .take(56)
-                .collect()
-        ).collect()
-    ).collect();
+    Ok(HttpResponse::Ok().body("")) // <- send response
+}
 
-    let recent_xy = x.recent_xy;
+/// Update the most recent position
+async fn post_xy(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body: XYData = decode_body(&req, payload, &auth, &device_id(&req)).await?;
+    let d = &mut *metrics::lock(&shared_state);
 
-    let sample = Sample {
-        date: Utc::now(),
-        x: recent_xy.0,
-        y: recent_xy.1,
-        csi: mm.clone(),
+    (*d).recent_xy = (body.x, body.y);
+
+    d.storage.add_position(Utc::now(), body.x, body.y)?;
+
+    Ok(HttpResponse::Ok().body("")) // <- send response
+}
+
+async fn index(filter: web::Query<RangeFilter>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+    let device = filter.device.as_deref();
+    let limit = filter.limit.unwrap_or(query::DEFAULT_LIMIT).min(query::MAX_LIMIT);
+
+    let samples = x.storage.query_last(&Filter {
+        device,
+        session: filter.session.as_deref(),
+        from: filter.from,
+        to: filter.to,
+    }, limit)?;
+    let csi: Vec<_> = samples.iter().map(|s| s.view(filter.values)).collect();
+
+    Ok(HttpResponse::Ok().json(csi))
+}
+
+/// Paginated samples with field selection and decimation, see `query::Params`
+async fn query_samples(params: web::Query<query::Params>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+    let page = query::run(&mut *x.storage, &params).map_err(error::ErrorBadRequest)?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+async fn get_one(filter: web::Query<DeviceFilter>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+    let last = x.storage.latest(filter.device.as_deref())?
+        .map(|s| s.view(filter.values));
+
+    Ok(HttpResponse::Ok().json(vec![last]))
+}
+
+/// Query parameters of `/export`
+#[derive(Clone, Debug, Deserialize)]
+struct ExportQuery {
+    format: String,
+    device: Option<String>,
+    session: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    dtype: Option<String>,
+}
+
+/// Push new samples to a WebSocket client, filtered by `stream::Params`
+async fn ws_stream(req: HttpRequest, payload: web::Payload, params: web::Query<stream::Params>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let sub = stream::Subscription::new(&params).map_err(error::ErrorBadRequest)?;
+    let x = &mut *metrics::lock(&shared_state);
+
+    stream::start(&req, payload, &mut x.hub, sub)
+}
+
+/// Per-device rate, RSSI and amplitude as Server-Sent Events, see `events::Params`
+async fn device_events(params: web::Query<events::Params>, shared_state: web::Data<Mutex<CSIData>>) -> HttpResponse {
+    events::start(params.into_inner(), shared_state)
+}
+
+/// Bytes of `/export` buffered before they are sent
+const EXPORT_CHUNK: usize = 65_536;
+/// Chunks queued for a client before the encoder waits for it
+const EXPORT_QUEUE: usize = 4;
+
+/// An export being encoded, sent to the response a chunk at a time
+struct ExportBody {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ExportBody {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= EXPORT_CHUNK {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        metrics::EXPORT_BYTES.fetch_add(chunk.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        // blocks the encoder while the client is slower than it
+        futures::executor::block_on(self.tx.send(Ok(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"))
+    }
+}
+
+/// Export matching samples from memory and disk as a file download.
+///
+/// The samples are taken from the storage under the lock, then encoded on
+/// a thread of their own and streamed, so ingestion does not wait for it.
+async fn export_samples(query: web::Query<ExportQuery>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let format: ExportFormat = query.format.parse().map_err(error::ErrorBadRequest)?;
+    let mut opts = export::Options::default();
+    if let Some(dtype) = &query.dtype {
+        opts.dtype = dtype.parse().map_err(error::ErrorBadRequest)?;
+    }
+
+    let device = query.device.as_deref();
+    let session = query.session.as_deref();
+    let snapshot = {
+        let x = &mut *metrics::lock(&shared_state);
+        if let Some(cfg) = &x.c {
+            opts.csv = cfg.csv.clone();
+        }
+        x.storage.snapshot(&Filter { device, session, from: query.from, to: query.to })?
+    };
+
+    let fname = format!(
+        "csi_{}_{}.{}",
+        session.or(device).map_or_else(|| "all".to_string(), device::sanitize),
+        file_timestamp(Utc::now()),
+        format.extension(),
+    );
+
+    let (tx, rx) = mpsc::channel(EXPORT_QUEUE);
+    let name = fname.clone();
+    std::thread::spawn(move || {
+        let mut body = ExportBody { tx, buf: vec![] };
+        if let Err(e) = snapshot.write(format, &opts, &mut body).and_then(|_| body.flush()) {
+            eprintln!("Export of {} failed: {}", name, e);
+            // ends the response with an error instead of a truncated file
+            let _ = futures::executor::block_on(body.tx.send(Err(e)));
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", fname))
+        .streaming(rx))
+}
+
+/// Per-device ingestion statistics
+async fn devices(_req: HttpRequest, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &*metrics::lock(&shared_state);
+
+    let stats: BTreeMap<_, _> = x.devices.iter()
+        .map(|(id, s)| (id, serde_json::json!({
+            "frames": s.frames,
+            "first_seen": s.first_seen,
+            "last_seen": s.last_seen,
+            "rate": s.rate(),
+        })))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+
+/// Devices, position, storage and output configuration
+async fn server_status(shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+
+    Ok(HttpResponse::Ok().json(status::Status::collect(x, Utc::now())?))
+}
+
+/// Inter-arrival and loss statistics of every device since start
+async fn frame_stats(filter: web::Query<DeviceFilter>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &*metrics::lock(&shared_state);
+
+    let stats: BTreeMap<_, _> = x.timing.iter()
+        .filter(|(id, _)| filter.device.as_ref().map_or(true, |d| d == *id))
+        .map(|(id, s)| (id, s.summary()))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Counters and gauges in the Prometheus text format
+async fn prometheus_metrics(shared_state: web::Data<Mutex<CSIData>>) -> HttpResponse {
+    let x = &*metrics::lock(&shared_state);
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(x, Utc::now()))
+}
+
+/// Recording sessions known to the storage
+async fn sessions(shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+
+    Ok(HttpResponse::Ok().json(x.storage.sessions()?))
+}
+
+/// Body of `/sessions/start`
+#[derive(Clone, Debug, Deserialize)]
+struct StartSession {
+    name: String,
+    #[serde(default)]
+    present: bool,
+    /// Fixed position; the positions posted to `/post_xy` are used without it
+    x: Option<f64>,
+    y: Option<f64>,
+}
+
+/// The session as stored, with its sample count
+fn session_info(x: &mut CSIData, name: &str) -> io::Result<Option<storage::SessionInfo>> {
+    Ok(x.storage.sessions()?.into_iter().find(|s| s.session.name == name))
+}
+
+/// Start a session, stopping the running one
+async fn start_session(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body: StartSession = decode_body(&req, payload, &auth, &device_id(&req)).await?;
+    let position = match (body.x, body.y) {
+        (Some(x), Some(y)) => Receive::Predefined(x, y),
+        (None, None) => Receive::Realtime,
+        _ => return Err(error::ErrorBadRequest("x and y go together")),
+    };
+    if body.name.trim().is_empty() {
+        return Err(error::ErrorBadRequest("empty session name"));
+    }
+
+    let x = &mut *metrics::lock(&shared_state);
+    let session = Session {
+        name: body.name,
+        started: Utc::now(),
+        present: body.present,
+        position,
+    };
+    x.start_session(session.clone()).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => error::ErrorConflict(e),
+        _ => e.into(),
+    })?;
+
+    Ok(HttpResponse::Ok().json(session_info(x, &session.name)?))
+}
+
+/// Stop the running session
+async fn stop_session(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body = read_body(payload).await?;
+    auth.check(&req, &device_id(&req), &body)?;
+
+    let x = &mut *metrics::lock(&shared_state);
+    match x.stop_session(Utc::now())? {
+        Some(stopped) => Ok(HttpResponse::Ok().json(session_info(x, &stopped.name)?)),
+        None => Err(error::ErrorConflict("no session is running")),
+    }
+}
+
+/// Every annotation, by start
+async fn annotations(shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+
+    Ok(HttpResponse::Ok().json(x.storage.annotations()?))
+}
+
+fn annotation_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => error::ErrorNotFound(e),
+        io::ErrorKind::InvalidInput => error::ErrorBadRequest(e),
+        _ => e.into(),
+    }
+}
+
+/// Store an annotation; `start` defaults to now and `end` may be set later
+async fn add_annotation(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body: Annotation = decode_body(&req, payload, &auth, &device_id(&req)).await?;
+    body.validate().map_err(error::ErrorBadRequest)?;
+
+    let x = &mut *metrics::lock(&shared_state);
+    Ok(HttpResponse::Ok().json(x.storage.add_annotation(body)?))
+}
+
+/// Body of `/annotations/end` and `/annotations/delete`
+#[derive(Clone, Debug, Deserialize)]
+struct AnnotationId {
+    id: u64,
+    /// Defaults to now
+    end: Option<DateTime<Utc>>,
+}
+
+/// Close an open annotation
+async fn end_annotation(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body: AnnotationId = decode_body(&req, payload, &auth, &device_id(&req)).await?;
+
+    let x = &mut *metrics::lock(&shared_state);
+    let annotation = x.storage.end_annotation(body.id, body.end.unwrap_or_else(Utc::now))
+        .map_err(annotation_error)?;
+
+    Ok(HttpResponse::Ok().json(annotation))
+}
+
+async fn delete_annotation(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body: AnnotationId = decode_body(&req, payload, &auth, &device_id(&req)).await?;
+
+    let x = &mut *metrics::lock(&shared_state);
+    x.storage.delete_annotation(body.id).map_err(annotation_error)?;
+
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// Query of `/sessions/trajectory`
+#[derive(Clone, Debug, Deserialize)]
+struct TrajectoryQuery {
+    session: String,
+    /// `csv` or `jsonl`; taken from the `Content-Type` or the file itself
+    /// without it
+    format: Option<String>,
+    /// Added to the trajectory's timestamps to get the server's time
+    #[serde(default)]
+    offset_ms: i64,
+    /// Longest stretch without a point to interpolate over
+    max_gap_ms: Option<i64>,
+}
+
+fn trajectory_format(req: &HttpRequest, query: &TrajectoryQuery, text: &str) -> Result<trajectory::Format, Error> {
+    if let Some(format) = &query.format {
+        return format.parse().map_err(error::ErrorBadRequest);
+    }
+
+    let mime = req.headers().get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(str::trim);
+    Ok(match mime {
+        Some("text/csv") => trajectory::Format::Csv,
+        Some("application/x-ndjson") | Some("application/jsonl") => trajectory::Format::JsonLines,
+        _ => trajectory::Format::detect(text),
+    })
+}
+
+/// Ground-truth trajectory of a session with how many of its samples it
+/// covers
+fn trajectory_report(x: &mut CSIData, session: &str, trajectory: &Trajectory) -> io::Result<trajectory::Report> {
+    let dates = x.storage.dates(&Filter {
+        session: Some(session),
+        ..Filter::default()
+    })?;
+
+    Ok(trajectory.report(&dates))
+}
+
+/// Import the ground-truth trajectory of a session, replacing any previous
+/// one
+async fn set_trajectory(req: HttpRequest, payload: web::Payload, query: web::Query<TrajectoryQuery>, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body = read_body_max(payload, trajectory::MAX_SIZE).await?;
+    auth.check(&req, &device_id(&req), &body)?;
+    let text = std::str::from_utf8(&body).map_err(error::ErrorBadRequest)?;
+    let format = trajectory_format(&req, &query, text)?;
+    let trajectory = Trajectory::parse(text, format, query.offset_ms, query.max_gap_ms)
+        .map_err(error::ErrorBadRequest)?;
+
+    let x = &mut *metrics::lock(&shared_state);
+    if session_info(x, &query.session)?.is_none() {
+        return Err(error::ErrorNotFound(format!("no session {}", query.session)));
+    }
+    x.storage.set_trajectory(&query.session, &trajectory)?;
+
+    Ok(HttpResponse::Ok().json(trajectory_report(x, &query.session, &trajectory)?))
+}
+
+/// Query of `GET /sessions/trajectory`
+#[derive(Clone, Debug, Deserialize)]
+struct SessionQuery {
+    session: String,
+}
+
+async fn get_trajectory(query: web::Query<SessionQuery>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &mut *metrics::lock(&shared_state);
+    match x.storage.trajectory(&query.session)? {
+        Some(trajectory) => Ok(HttpResponse::Ok().json(trajectory_report(x, &query.session, &trajectory)?)),
+        None => Err(error::ErrorNotFound(format!("no trajectory for session {}", query.session))),
+    }
+}
+
+/// Progress of the site survey, every point with its samples and flags
+async fn survey_progress(shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let x = &*metrics::lock(&shared_state);
+
+    match &x.survey {
+        Some(survey) => Ok(HttpResponse::Ok().json(survey)),
+        None => Err(error::ErrorNotFound("no survey, see --survey")),
+    }
+}
+
+/// Optional body of `/survey/next`
+#[derive(Clone, Debug, Deserialize)]
+struct SurveyNext {
+    /// Collect this point again instead of the current one
+    point: Option<usize>,
+    /// Finish the point being collected now and flag it
+    #[serde(default)]
+    force: bool,
+}
+
+/// The operator is at the current survey point; start collecting
+async fn survey_next(req: HttpRequest, payload: web::Payload, auth: web::Data<Auth>, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
+    let body = read_body(payload).await?;
+    auth.check(&req, &device_id(&req), &body)?;
+    let next = if body.is_empty() {
+        SurveyNext { point: None, force: false }
+    } else {
+        codec::Format::from_request(&req)?.decode::<SurveyNext>(&body)?
     };
 
-    // *shared_state.lock().unwrap() =
-    // *x = CSIData { inner: mm.clone() };
-    (*x).inner.push(mm.clone());
-    (*x).samples.push(sample.clone());
+    let x = &mut *metrics::lock(&shared_state);
+    let survey = x.survey.as_mut().ok_or_else(|| error::ErrorNotFound("no survey, see --survey"))?;
+    if next.force {
+        let index = survey.finish(Utc::now()).map_err(error::ErrorConflict)?;
+        survey.announce(index);
+    } else {
+        survey.confirm(next.point, Utc::now()).map_err(error::ErrorConflict)?;
+        println!("{}", survey.prompt());
+    }
 
+    Ok(HttpResponse::Ok().json(survey.summary()))
+}
 
-    if let Some(cfg) = (*x).c.clone() {
-        if x.samples.len() == cfg.write_at_least {
-            save_collected(&x.samples);
+/// Confirm survey points from the terminal: Enter for the current point,
+/// the number of a point to collect again, or `f` to finish the point being
+/// collected
+fn survey_prompt(shared_state: web::Data<Mutex<CSIData>>) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let (point, force) = match line.trim() {
+            "" => (None, false),
+            "f" => (None, true),
+            n => match n.parse() {
+                Ok(i) => (Some(i), false),
+                Err(_) => {
+                    eprintln!("Press Enter, enter the number of a point to collect again, or f to finish the current one");
+                    continue;
+                }
+            },
+        };
 
-            println!("Done saving");
+        let x = &mut *metrics::lock(&shared_state);
+        if let Some(survey) = x.survey.as_mut() {
+            if force {
+                match survey.finish(Utc::now()) {
+                    Ok(index) => survey.announce(index),
+                    Err(e) => eprintln!("{}", e),
+                }
+                continue;
+            }
+            match survey.confirm(point, Utc::now()) {
+                Ok(_) => println!("{}", survey.prompt()),
+                Err(e) => eprintln!("{}", e),
+            }
         }
     }
+}
 
-    Ok(HttpResponse::Ok().body("")) // <- send response
+/// Storage written by a previous run, for the `export` subcommand
+fn previous_run(e: &ExportOpt) -> io::Result<Box<dyn Storage>> {
+    match (&e.spill_dir, &e.db) {
+        (Some(dir), _) => Ok(Box::new(Spill::open(dir, 1)?)),
+        (None, Some(path)) => open_db(path, 1),
+        (None, None) => Err(io::Error::new(io::ErrorKind::InvalidInput, "--spill-dir or --db is required")),
+    }
 }
 
-/// Update the most recent position
-async fn post_xy(mut payload: web::Payload, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
-    let mut body = BytesMut::new();
-    let d = &mut *shared_state.lock().unwrap();
-
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+#[cfg(feature = "sqlite")]
+fn open_db(path: &Path, batch: usize) -> io::Result<Box<dyn Storage>> {
+    Ok(Box::new(db::Db::open(path, batch)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_db(_path: &Path, _batch: usize) -> io::Result<Box<dyn Storage>> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "--db needs the `sqlite` feature"))
+}
+
+/// Storage backend selected on the command line
+fn open_storage(opt: &Opt) -> io::Result<Box<dyn Storage>> {
+    let backend = opt.storage.unwrap_or(
+        if opt.db.is_some() { Backend::Sqlite } else { Backend::Memory }
+    );
+
+    match backend {
+        Backend::Memory => {
+            let retention = Retention {
+                max_samples: opt.keep_samples,
+                max_age: opt.keep_minutes.map(chrono::Duration::minutes),
+            };
+            let spill = match &opt.spill_dir {
+                Some(dir) => Some(Spill::open(dir, opt.spill_segment.unwrap_or(SPILL_SEGMENT))?),
+                None => None,
+            };
+
+            Ok(Box::new(SampleStore::new(retention, spill)))
         }
-        body.extend_from_slice(&chunk);
+        Backend::File => match &opt.spill_dir {
+            Some(dir) => Ok(Box::new(Spill::open(dir, opt.spill_segment.unwrap_or(SPILL_SEGMENT))?)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "--storage file needs --spill-dir")),
+        },
+        Backend::Sqlite => match &opt.db {
+            Some(path) => open_db(path, opt.db_batch.unwrap_or(DB_BATCH)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "--storage sqlite needs --db")),
+        },
     }
+}
 
-    let body: XYData = bincode::deserialize(&body)
-        .unwrap();
+/// `export` subcommand: write samples from a previous run to a file
+fn export_spilled(e: ExportOpt) -> io::Result<()> {
+    if e.partition {
+        return export_partitioned(e);
+    }
 
-    (*d).recent_xy = (body.x, body.y);
+    let format = match e.format {
+        Some(f) => f,
+        None => e.out.extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+    };
 
+    let opts = export::Options {
+        csv: e.csv.clone(),
+        dtype: e.dtype,
+    };
+    let mut out = io::BufWriter::new(std::fs::File::create(&e.out)?);
+    let n = previous_run(&e)?.export(format, &opts, &Filter {
+        device: e.device.as_deref(),
+        session: e.session.as_deref(),
+        from: e.from,
+        to: e.to,
+    }, &mut out)?;
 
-    Ok(HttpResponse::Ok().body("")) // <- send response
+    println!("Exported {} samples to {}", n, e.out.display());
+    Ok(())
+}
+
+/// `export --partition`: write spilled samples as a Parquet dataset
+#[cfg(feature = "columnar")]
+fn export_partitioned(e: ExportOpt) -> io::Result<()> {
+    let samples = previous_run(&e)?.query(&Filter {
+        device: e.device.as_deref(),
+        session: e.session.as_deref(),
+        from: e.from,
+        to: e.to,
+    })?;
+
+    let mut dataset = export::parquet::Dataset::new(&e.out, 10000)?;
+    for s in &samples {
+        dataset.write(s)?;
+    }
+    dataset.close()?;
+
+    println!("Exported {} samples to {}", samples.len(), e.out.display());
+    Ok(())
 }
 
-async fn index(_req: HttpRequest, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
-    let x = &*shared_state.lock().unwrap();
-    // let data = data.data;
-    
-    // shared
-    Ok(HttpResponse::Ok().json(x.inner.clone()))
+#[cfg(not(feature = "columnar"))]
+fn export_partitioned(_e: ExportOpt) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "--partition needs the `columnar` feature"))
 }
 
-async fn get_one(_req: HttpRequest, shared_state: web::Data<Mutex<CSIData>>) -> Result<HttpResponse, Error> {
-    let x = &*shared_state.lock().unwrap();
-    // let data = data.data;
-    
-    // shared
-    Ok(HttpResponse::Ok().json(vec![x.inner.last().clone()]))
+/// CSV columns of the output files and exports
+fn csv_config(opt: &Opt) -> CsvConfig {
+    let defaults = CsvConfig::default();
+
+    CsvConfig {
+        pairs: opt.csv_pairs.clone().unwrap_or(defaults.pairs),
+        values: opt.csv_values.unwrap_or(defaults.values),
+        meta: opt.csv_meta.clone().map_or(defaults.meta, |m| m.0),
+    }
 }
 
+/// The server state described by the flags: storage, outputs and survey
+fn open_state(opt: &Opt) -> io::Result<CSIData> {
+    let survey = match &opt.survey {
+        Some(path) => Some(Survey::new(survey::load_points(path)?, survey::Target {
+            samples: opt.survey_samples,
+            secs: opt.survey_secs,
+            min_samples: opt.survey_min_samples.or(opt.survey_samples).unwrap_or(1),
+            min_rssi: opt.survey_min_rssi,
+            max_bad: opt.survey_max_bad.unwrap_or(SURVEY_MAX_BAD),
+        })),
+        None => None,
+    };
+
+    let mut storage = open_storage(opt)?;
+
+    let rotation = Rotation {
+        max_samples: opt.write_at_least,
+        max_bytes: opt.rotate_mb.map(|mb| mb * 1024 * 1024),
+        max_age: opt.rotate_minutes.map(|m| Duration::from_secs(m * 60)),
+    };
+    let c = if opt.no_output {
+        None
+    } else {
+        Some(WriteConfig {
+            out_dir: opt.out_dir.clone().unwrap_or_else(|| PathBuf::from(".")),
+            rotation,
+            flush_every: Duration::from_secs(opt.flush_secs.unwrap_or(FLUSH_SECS).max(1)),
+            checkpoint_every: opt.checkpoint_secs.map(|s| Duration::from_secs(s.max(1))),
+            csv: csv_config(opt),
+        })
+    };
+    // files are written as samples arrive only when asked for; otherwise
+    // `CSIData::close` saves what is held in memory on shutdown
+    let output = match &c {
+        Some(cfg) if opt.out_dir.is_some() || opt.write_at_least.is_some() => {
+            Some(Output::new(&cfg.out_dir, cfg.rotation.clone(), cfg.csv.clone())?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "columnar")]
+    let dataset = match &opt.parquet_dir {
+        Some(dir) if !opt.no_output => Some(export::parquet::Dataset::new(dir, opt.parquet_row_group.unwrap_or(PARQUET_ROW_GROUP))?),
+        _ => None,
+    };
+    #[cfg(not(feature = "columnar"))]
+    {
+        if opt.parquet_dir.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--parquet-dir needs the `columnar` feature"));
+        }
+    }
+
+    // pick up where a previous run stopped
+    let known_devices = storage.devices()?;
+    let recent_xy = storage.last_position()?.unwrap_or((-1.0, -1.0));
+    let running = storage.sessions()?.into_iter()
+        .rev()
+        .find(|s| s.stopped.is_none())
+        .map(|s| s.session);
+
+    Ok(CSIData {
+        c,
+        output,
+        #[cfg(feature = "columnar")]
+        dataset,
+
+        recent_xy,
+        session: running,
+        survey,
+
+        storage,
+
+        devices: known_devices,
+        signal: BTreeMap::new(),
+        latest: BTreeMap::new(),
+        timing: BTreeMap::new(),
+
+        hub: stream::Hub::default(),
+
+        started: Utc::now(),
+        accepting: true,
+    })
+}
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let opt = Opt::from_args();
-    env_logger::init();
-
-    let shared_data = web::Data::new(Mutex::new(
-        CSIData {
-            inner: vec![] ,
-            
-            c: if opt.write_at_least.is_some() {
-                Some(WriteConfig {
-                    write_at_least: opt.write_at_least.unwrap(),
-                    data: Receive::Realtime,
-                })
-            } else {
-                None
-            },
+    let mut opt = Opt::from_args();
+    let level = if opt.debug { "debug" } else { "error" };
+    env_logger::from_env(env_logger::Env::default().default_filter_or(level)).init();
 
-            recent_xy: (-1.0, -1.0),
+    if let Some(path) = opt.config.clone() {
+        opt.apply(config::Config::load(path)?);
+    }
+
+    if let Some(Command::Export(mut e)) = opt.cmd.take() {
+        e.apply(&opt);
+        return export_spilled(e);
+    }
 
-            samples: vec![],
+    let addr = opt.addr.clone().ok_or_else(
+        || io::Error::new(io::ErrorKind::InvalidInput, "--addr is required")
+    )?;
+    if opt.session.is_none() && (opt.is_present || opt.x.is_some()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "-p, -x and -y describe a --session"));
+    }
+    if opt.x.is_some() != opt.y.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "-x and -y go together"));
+    }
+    if opt.survey.is_some() && opt.x.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--survey gives the positions, -x and -y cannot be used with it"));
+    }
+    if opt.survey.is_some() && opt.survey_samples.is_none() && opt.survey_secs.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--survey needs --survey-samples or --survey-secs"));
+    }
+    if opt.tls_cert.is_some() != opt.tls_key.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--tls-cert and --tls-key go together"));
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        if opt.tls_cert.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--tls-cert needs the `tls` feature"));
+        }
+    }
+
+    let keys = match (&opt.auth_keys, &opt.auth_token) {
+        (Some(path), token) => {
+            let mut keys = auth::Keys::load(path)?;
+            if let Some(token) = token {
+                keys.set_shared(token.clone());
+            }
+            Some(keys)
+        }
+        (None, Some(token)) => Some(auth::Keys::shared(token.clone())),
+        (None, None) => None,
+    };
+    let auth = web::Data::new(Auth { mode: opt.auth.unwrap_or(auth::Mode::Token), keys });
+
+    let flush_every = Duration::from_secs(opt.flush_secs.unwrap_or(FLUSH_SECS).max(1));
+    let checkpoint_every = opt.checkpoint_secs.map(|s| Duration::from_secs(s.max(1)));
+    let shared_data = web::Data::new(Mutex::new(open_state(&opt)?));
+
+    if let Some(name) = &opt.session {
+        let x = &mut *metrics::lock(&shared_data);
+        let position = match (opt.x, opt.y) {
+            (Some(px), Some(py)) => Receive::Predefined(px, py),
+            _ => Receive::Realtime,
+        };
+
+        if x.session.as_ref().map_or(false, |s| &s.name == name) {
+            println!("Resuming session {}", name);
+        } else {
+            x.start_session(Session {
+                name: name.clone(),
+                started: Utc::now(),
+                present: opt.is_present,
+                position,
+            })?;
+            println!("Started session {}", name);
+        }
+    }
+
+    {
+        let timer_data = shared_data.clone();
+        actix_rt::spawn(async move {
+            let mut ticks = actix_rt::time::interval(flush_every);
+            loop {
+                ticks.tick().await;
+
+                if let Err(e) = metrics::lock(&timer_data).flush() {
+                    eprintln!("Failed to flush output: {}", e);
+                }
+            }
+        });
+    }
+
+    if let Some(every) = checkpoint_every {
+        let timer_data = shared_data.clone();
+        actix_rt::spawn(async move {
+            let mut ticks = actix_rt::time::interval(every);
+            loop {
+                ticks.tick().await;
+
+                if let Err(e) = metrics::lock(&timer_data).checkpoint() {
+                    eprintln!("Checkpoint failed: {}", e);
+                }
+            }
+        });
+    }
+
+    let surveying = match &metrics::lock(&shared_data).survey {
+        Some(survey) => {
+            println!("Survey of {} points", survey.points.len());
+            println!("{}", survey.prompt());
+            true
         }
-    ));
+        None => false,
+    };
+    if surveying {
+        let timer_data = shared_data.clone();
+        actix_rt::spawn(async move {
+            let mut ticks = actix_rt::time::interval(Duration::from_secs(1));
+            loop {
+                ticks.tick().await;
+
+                let x = &mut *metrics::lock(&timer_data);
+                if let Some(survey) = x.survey.as_mut() {
+                    if let Some(point) = survey.tick(Utc::now()) {
+                        survey.announce(point);
+                    }
+                }
+            }
+        });
+
+        let prompt_data = shared_data.clone();
+        std::thread::spawn(move || survey_prompt(prompt_data));
+    }
 
-    HttpServer::new(move || {
+    let server_data = shared_data.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(shared_data.clone())
+            .app_data(server_data.clone())
+            .app_data(auth.clone())
             .wrap(middleware::Logger::default())
             .service(web::resource("/csi").route(web::post().to(post_csi)))
             .service(web::resource("/post_xy").route(web::post().to(post_xy)))
             .service(web::resource("/get").to(index))
+            .service(web::resource("/query").to(query_samples))
             .service(web::resource("/get_one").to(get_one))
+            .service(web::resource("/ws").route(web::get().to(ws_stream)))
+            .service(web::resource("/events").route(web::get().to(device_events)))
+            .service(web::resource("/devices").to(devices))
+            .service(web::resource("/status").to(server_status))
+            .service(web::resource("/stats").to(frame_stats))
+            .service(web::resource("/metrics").to(prometheus_metrics))
+            .service(web::resource("/sessions").to(sessions))
+            .service(web::resource("/sessions/start").route(web::post().to(start_session)))
+            .service(web::resource("/sessions/stop").route(web::post().to(stop_session)))
+            .service(web::resource("/sessions/trajectory")
+                .route(web::get().to(get_trajectory))
+                .route(web::post().to(set_trajectory)))
+            .service(web::resource("/annotations").to(annotations))
+            .service(web::resource("/annotations/add").route(web::post().to(add_annotation)))
+            .service(web::resource("/annotations/end").route(web::post().to(end_annotation)))
+            .service(web::resource("/annotations/delete").route(web::post().to(delete_annotation)))
+            .service(web::resource("/survey").to(survey_progress))
+            .service(web::resource("/survey/next").route(web::post().to(survey_next)))
+            .service(web::resource("/export").to(export_samples))
     })
-        .bind(opt.addr)?
-        .run()
-        .await
+        .disable_signals()
+        .shutdown_timeout(opt.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT_SECS));
+
+    let server = match (&opt.tls_cert, &opt.tls_key) {
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => server.bind_rustls(addr, tls::server_config(cert, key)?)?,
+        _ => server.bind(addr)?,
+    }
+        .run();
+
+    let stop_server = server.clone();
+    let stop_data = shared_data.clone();
+    actix_rt::spawn(async move {
+        shutdown::wait_for_signal().await;
+
+        println!("Shutting down, draining in-flight requests...");
+        metrics::lock(&stop_data).accepting = false;
+        stop_server.stop(true).await;
+    });
+
+    server.await?;
+
+    metrics::lock(&shared_data).close()?;
+    println!("All samples saved");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{sample, temp_dir};
+
+    #[test]
+    fn default_flags_keep_samples() {
+        // the default output directory is the current one
+        let dir = temp_dir("default_flags");
+        std::env::set_current_dir(&dir).unwrap();
+
+        let opt = Opt::from_iter(&["recv_csi_server", "--addr", "127.0.0.1:8899"]);
+        let mut x = open_state(&opt).unwrap();
+        for i in 0..3 {
+            x.push(sample("a", Utc::now(), i)).unwrap();
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        x.close().unwrap();
+
+        let files: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].file_name().unwrap().to_str().unwrap().starts_with("csi_data_a_"));
+        // a header and a row per sample
+        assert_eq!(std::fs::read_to_string(&files[0]).unwrap().lines().count(), 4);
+
+        // nothing to save when the storage keeps the samples itself
+        let spill = dir.join("spill");
+        let opt = Opt::from_iter(&["recv_csi_server", "--addr", "127.0.0.1:8899", "--spill-dir", spill.to_str().unwrap()]);
+        let mut x = open_state(&opt).unwrap();
+        x.push(sample("b", Utc::now(), 1)).unwrap();
+        x.close().unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn export_uses_config() {
+        let dir = temp_dir("export_config");
+        let path = dir.join("server.toml");
+        std::fs::write(&path, "[storage]\nspill_dir = \"data/spill\"\n\n[output.csv]\nvalues = \"phase\"\n").unwrap();
+
+        let mut opt = Opt::from_iter(&["recv_csi_server", "--config", path.to_str().unwrap(), "export", "--out", "a.csv"]);
+        opt.apply(config::Config::load(&path).unwrap());
+        let mut e = match opt.cmd.take() {
+            Some(Command::Export(e)) => e,
+            None => panic!("no subcommand"),
+        };
+        e.apply(&opt);
+        assert_eq!(e.spill_dir, Some(PathBuf::from("data/spill")));
+        assert_eq!(e.csv.values, Values::Phase);
+
+        // the subcommand's own storage wins
+        let mut e = ExportOpt::from_iter(&["export", "--db", "csi.db", "--out", "a.csv"]);
+        e.apply(&opt);
+        assert_eq!((e.spill_dir, e.db), (None, Some(PathBuf::from("csi.db"))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
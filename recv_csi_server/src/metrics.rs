@@ -0,0 +1,212 @@
+//! Prometheus metrics for `/metrics`, in the text exposition format
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use chrono::prelude::*;
+use csi_types::CSIStruct;
+
+use crate::types::CSIData;
+
+/// Bodies that could not be decoded
+pub static DECODE_FAILURES: AtomicU64 = AtomicU64::new(0);
+/// Bodies rejected for being larger than `codec::MAX_SIZE`
+pub static PAYLOAD_TOO_LARGE: AtomicU64 = AtomicU64::new(0);
+/// Requests rejected for missing or wrong credentials
+pub static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+/// Bytes of files sent by `/export`
+pub static EXPORT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+static LOCKS: AtomicU64 = AtomicU64::new(0);
+static LOCK_WAIT_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Lock the shared state, recording how long it took.
+///
+/// A handler that panicked while holding the lock poisons it. At worst the
+/// request it was serving is half-applied, so the poison is logged and
+/// cleared rather than failing every later request, timer and the shutdown.
+pub fn lock(state: &Mutex<CSIData>) -> MutexGuard<'_, CSIData> {
+    let start = Instant::now();
+    let guard = state.lock().unwrap_or_else(|e| {
+        eprintln!("A request panicked while holding the server state, carrying on");
+        state.clear_poison();
+        e.into_inner()
+    });
+
+    LOCKS.fetch_add(1, Ordering::Relaxed);
+    LOCK_WAIT_MICROS.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    guard
+}
+
+/// Weight of a new frame in the signal averages, about the last 64 frames
+const SMOOTHING: f64 = 1.0 / 64.0;
+
+/// Moving averages of a device's RSSI per chain and noise floor
+#[derive(Clone, Debug, Default)]
+pub struct Signal {
+    pub rssi: [Option<f64>; 3],
+    pub noise: Option<f64>,
+}
+
+fn smooth(avg: &mut Option<f64>, value: u8) {
+    let value = f64::from(value);
+    *avg = Some(match *avg {
+        Some(a) => a + SMOOTHING * (value - a),
+        None => value,
+    });
+}
+
+impl Signal {
+    /// Account a frame header; only chains the frame was received on count
+    pub fn update(&mut self, st: &CSIStruct) {
+        let chains = [st.rssi_0, st.rssi_1, st.rssi_2];
+        for (avg, &rssi) in self.rssi.iter_mut().zip(&chains).take(st.nr.max(1) as usize) {
+            smooth(avg, rssi);
+        }
+        smooth(&mut self.noise, st.noise);
+    }
+}
+
+/// Builder of the exposition text
+struct Text(String);
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Text {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+/// Current values of every metric
+pub fn render(x: &CSIData, now: DateTime<Utc>) -> String {
+    let mut t = Text(String::new());
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+
+    t.family("csi_frames_received_total", "counter", "Frames received per device");
+    for (id, stats) in &x.devices {
+        t.sample("csi_frames_received_total", &[("device", id)], stats.frames as f64);
+    }
+
+    t.family("csi_device_last_frame_age_seconds", "gauge", "Seconds since the device's last frame");
+    for (id, stats) in &x.devices {
+        let age = (now - stats.last_seen).num_milliseconds() as f64 / 1000.0;
+        t.sample("csi_device_last_frame_age_seconds", &[("device", id)], age);
+    }
+
+    t.family("csi_rssi", "gauge", "RSSI per receive chain, averaged over about the last 64 frames");
+    for (id, signal) in &x.signal {
+        for (chain, rssi) in signal.rssi.iter().enumerate() {
+            if let Some(rssi) = rssi {
+                t.sample("csi_rssi", &[("device", id), ("chain", &chain.to_string())], *rssi);
+            }
+        }
+    }
+
+    t.family("csi_noise", "gauge", "Noise floor, averaged over about the last 64 frames");
+    for (id, signal) in &x.signal {
+        if let Some(noise) = signal.noise {
+            t.sample("csi_noise", &[("device", id)], noise);
+        }
+    }
+
+    t.family("csi_frame_rate_hz", "gauge", "Frames per second of hardware time since start");
+    for (id, timing) in &x.timing {
+        t.sample("csi_frame_rate_hz", &[("device", id)], timing.rate());
+    }
+
+    t.family("csi_interval_jitter_seconds", "gauge", "Standard deviation of the hardware inter-arrival interval");
+    for (id, timing) in &x.timing {
+        t.sample("csi_interval_jitter_seconds", &[("device", id)], timing.jitter_us() / 1e6);
+    }
+
+    t.family("csi_frames_lost_total", "counter", "Frames missing according to 802.11 sequence numbers");
+    for (id, timing) in &x.timing {
+        t.sample("csi_frames_lost_total", &[("device", id)], timing.lost as f64);
+    }
+
+    t.family("csi_decode_failures_total", "counter", "Request bodies that could not be decoded");
+    t.sample("csi_decode_failures_total", &[], load(&DECODE_FAILURES));
+
+    t.family("csi_payload_too_large_total", "counter", "Request bodies rejected for exceeding the size limit");
+    t.sample("csi_payload_too_large_total", &[], load(&PAYLOAD_TOO_LARGE));
+
+    t.family("csi_auth_failures_total", "counter", "Requests rejected for missing or wrong credentials");
+    t.sample("csi_auth_failures_total", &[], load(&AUTH_FAILURES));
+
+    t.family("csi_mutex_wait_seconds_total", "counter", "Time spent waiting for the shared state lock");
+    t.sample("csi_mutex_wait_seconds_total", &[], load(&LOCK_WAIT_MICROS) / 1e6);
+
+    t.family("csi_mutex_locks_total", "counter", "Times the shared state lock was taken");
+    t.sample("csi_mutex_locks_total", &[], load(&LOCKS));
+
+    t.family("csi_samples_in_memory", "gauge", "Samples held in memory by the storage");
+    t.sample("csi_samples_in_memory", &[], x.storage.in_memory() as f64);
+
+    t.family("csi_output_bytes_written_total", "counter", "Bytes written to the CSV output files");
+    t.sample("csi_output_bytes_written_total", &[], x.output.as_ref().map_or(0, |o| o.bytes_written()) as f64);
+
+    t.family("csi_export_bytes_total", "counter", "Bytes of files sent by /export");
+    t.sample("csi_export_bytes_total", &[], load(&EXPORT_BYTES));
+
+    t.family("csi_stream_subscribers", "gauge", "Connected /ws clients");
+    t.sample("csi_stream_subscribers", &[], x.hub.subscribers() as f64);
+
+    t.family("csi_stream_dropped_frames_total", "counter", "Frames dropped for slow /ws clients");
+    t.sample("csi_stream_dropped_frames_total", &[], x.hub.dropped as f64);
+
+    t.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        let mut t = Text(String::new());
+        t.family("csi_x_total", "counter", "Some counter");
+        t.sample("csi_x_total", &[("device", "a\"b")], 3.0);
+        t.sample("csi_x_total", &[], 0.5);
+
+        assert_eq!(t.0, "# HELP csi_x_total Some counter\n\
+                         # TYPE csi_x_total counter\n\
+                         csi_x_total{device=\"a\\\"b\"} 3\n\
+                         csi_x_total 0.5\n");
+    }
+
+    #[test]
+    fn signal_average() {
+        let mut st = CSIStruct::new();
+        st.nr = 2;
+        st.rssi_0 = 40;
+        st.rssi_1 = 30;
+        st.rssi_2 = 99;
+        st.noise = 10;
+
+        let mut signal = Signal::default();
+        signal.update(&st);
+        assert_eq!(signal.rssi, [Some(40.0), Some(30.0), None]);
+
+        st.rssi_0 = 104;
+        signal.update(&st);
+        assert_eq!(signal.rssi[0], Some(41.0));
+        assert_eq!(signal.noise, Some(10.0));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::common::file_timestamp;
+use crate::device::sanitize;
+use crate::export::csv::{CsvConfig, Layout};
+use crate::types::Sample;
+
+/// When to close the current file and start a new one
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rotation {
+    pub max_samples: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// Counts bytes as they leave the csv writer's buffer
+struct Counter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct RollingFile {
+    path: PathBuf,
+    writer: csv::Writer<Counter<File>>,
+    layout: Layout,
+    opened: DateTime<Utc>,
+    samples: usize,
+}
+
+impl RollingFile {
+    fn is_full(&self, rotation: &Rotation, now: DateTime<Utc>) -> bool {
+        rotation.max_samples.map_or(false, |max| self.samples >= max)
+            || rotation.max_bytes.map_or(false, |max| self.writer.get_ref().bytes >= max)
+            || rotation.max_age.map_or(false, |max| {
+                (now - self.opened).to_std().map_or(false, |age| age >= max)
+            })
+    }
+
+    fn close(mut self) -> io::Result<PathBuf> {
+        self.writer.flush()?;
+        self.writer.get_ref().inner.sync_all()?;
+
+        Ok(self.path)
+    }
+}
+
+/// Continuous CSV output, one rolling file per device
+pub struct Output {
+    dir: PathBuf,
+    rotation: Rotation,
+    csv: CsvConfig,
+    files: BTreeMap<String, RollingFile>,
+    /// Bytes written to files that are already closed
+    closed_bytes: u64,
+}
+
+impl Output {
+    pub fn new<P: AsRef<Path>>(dir: P, rotation: Rotation, csv: CsvConfig) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            rotation,
+            csv,
+            files: BTreeMap::new(),
+            closed_bytes: 0,
+        })
+    }
+
+    /// Create a new file that does not overwrite an existing one.
+    ///
+    /// The columns of the file are fixed by `first`, its first sample.
+    fn open(&self, first: &Sample, now: DateTime<Utc>) -> io::Result<RollingFile> {
+        let base = format!("csi_data_{}_{}", sanitize(&first.device), file_timestamp(now));
+
+        let mut n = 0;
+        loop {
+            let name = if n == 0 {
+                format!("{}.csv", base)
+            } else {
+                format!("{}-{}.csv", base, n)
+            };
+            let path = self.dir.join(name);
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let layout = Layout::new(&self.csv, first);
+                    let mut writer = csv::Writer::from_writer(Counter { inner: file, bytes: 0 });
+                    writer.write_record(layout.header())?;
+
+                    return Ok(RollingFile {
+                        path,
+                        writer,
+                        layout,
+                        opened: now,
+                        samples: 0,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn write(&mut self, s: &Sample) -> io::Result<()> {
+        let now = Utc::now();
+        self.rotate(now)?;
+
+        if !self.files.contains_key(&s.device) {
+            let file = self.open(s, now)?;
+            self.files.insert(s.device.clone(), file);
+        }
+
+        let file = self.files.get_mut(&s.device).unwrap();
+        let row = file.layout.record(s);
+        file.writer.write_record(row)?;
+        file.samples += 1;
+
+        Ok(())
+    }
+
+    /// Close files that reached their rotation limit
+    pub fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let rotation = &self.rotation;
+        let full: Vec<String> = self.files.iter()
+            .filter(|(_, f)| f.is_full(rotation, now))
+            .map(|(d, _)| d.clone())
+            .collect();
+
+        for device in full {
+            if let Some(file) = self.files.remove(&device) {
+                self.closed_bytes += file.writer.get_ref().bytes;
+                let path = file.close()?;
+                println!("Done saving {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bytes written since start, rows still buffered not included
+    pub fn bytes_written(&self) -> u64 {
+        self.closed_bytes + self.files.values().map(|f| f.writer.get_ref().bytes).sum::<u64>()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for file in self.files.values_mut() {
+            file.writer.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush buffered rows and wait until they reach the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        for file in self.files.values_mut() {
+            file.writer.flush()?;
+            file.writer.get_ref().inner.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush and close every open file
+    pub fn close(&mut self) -> io::Result<()> {
+        let files = std::mem::replace(&mut self.files, BTreeMap::new());
+        for (_, file) in files {
+            self.closed_bytes += file.writer.get_ref().bytes;
+            let path = file.close()?;
+            println!("Done saving {}", path.display());
+        }
+
+        Ok(())
+    }
+}
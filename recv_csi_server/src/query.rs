@@ -0,0 +1,523 @@
+//! Paginated sample queries for `/query`
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use csi_types::ser::ComplexDef;
+use csi_types::CSIStruct;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+use crate::store::Filter;
+use crate::types::Sample;
+
+/// Samples per page unless `limit` says otherwise
+pub const DEFAULT_LIMIT: usize = 500;
+/// Upper bound for `limit`
+pub const MAX_LIMIT: usize = 10_000;
+/// Raw samples read from the storage at a time while a page is built
+const CHUNK: usize = 1000;
+/// Raw samples read for one page at most; the page ends early with a
+/// cursor once they are used up
+pub const MAX_SCANNED: usize = 100_000;
+
+/// Position after the last sample of a page.
+///
+/// Samples are ordered by date; `skip` counts the samples at `date` that
+/// were already returned, so equal timestamps never repeat or get lost.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cursor {
+    pub date: DateTime<Utc>,
+    pub skip: usize,
+}
+
+impl Cursor {
+    /// The cursor after one more sample, captured at `date`
+    fn step(cursor: Option<Cursor>, date: DateTime<Utc>) -> Cursor {
+        match cursor {
+            Some(c) if c.date == date => Cursor { date, skip: c.skip + 1 },
+            _ => Cursor { date, skip: 1 },
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.date.timestamp() * 1_000_000 + self.date.timestamp_subsec_micros() as i64;
+        write!(f, "{}.{}", micros, self.skip)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor '{}'", s);
+        let mut parts = s.splitn(2, '.');
+        let micros: i64 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+        let skip: usize = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+        let date = Utc.timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32)
+            .single()
+            .ok_or_else(invalid)?;
+
+        Ok(Cursor { date, skip })
+    }
+}
+
+/// Parts of a sample included in the response
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Amplitude,
+    Phase,
+    Complex,
+    Meta,
+    Position,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "amplitude" => Ok(Field::Amplitude),
+            "phase" => Ok(Field::Phase),
+            "complex" => Ok(Field::Complex),
+            "meta" => Ok(Field::Meta),
+            "position" => Ok(Field::Position),
+            _ => Err(format!("unknown field '{}', expected amplitude, phase, complex, meta or position", s)),
+        }
+    }
+}
+
+/// Query string of `/query`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Params {
+    pub device: Option<String>,
+    pub session: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// `next` of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    /// Comma separated `Field`s, `amplitude,meta,position` by default
+    pub fields: Option<String>,
+    /// Keep every N-th sample of each device
+    pub every: Option<usize>,
+    /// Average amplitude and position over buckets of this many milliseconds
+    pub average_ms: Option<u64>,
+}
+
+impl Params {
+    pub fn fields(&self) -> Result<Vec<Field>, String> {
+        match &self.fields {
+            Some(list) => list.split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(str::parse)
+                .collect(),
+            None => Ok(vec![Field::Amplitude, Field::Meta, Field::Position]),
+        }
+    }
+}
+
+/// A sample, or the average of a bucket of samples, with the selected fields
+#[derive(Clone, Debug, Serialize)]
+pub struct Point {
+    pub date: DateTime<Utc>,
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Number of averaged samples
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<CSIStruct>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amplitude: Option<Vec<Vec<Vec<f64>>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Vec<Vec<Vec<f64>>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csi: Option<Vec<Vec<Vec<ComplexDef<isize>>>>>,
+}
+
+impl Point {
+    fn new(s: &Sample, fields: &[Field]) -> Self {
+        let has = |f| fields.contains(&f);
+        let (nr, nc, tones) = s.dims();
+
+        Point {
+            date: s.date,
+            device: s.device.clone(),
+            session: s.session.clone(),
+            count: None,
+            x: if has(Field::Position) { Some(s.x) } else { None },
+            y: if has(Field::Position) { Some(s.y) } else { None },
+            meta: if has(Field::Meta) { Some(s.status.clone()) } else { None },
+            amplitude: if has(Field::Amplitude) { Some(s.amplitude()) } else { None },
+            phase: if has(Field::Phase) { Some(s.phase()) } else { None },
+            csi: if has(Field::Complex) {
+                Some(s.csi[..nr].iter()
+                    .map(|r| r[..nc].iter().map(|c| c[..tones].to_vec()).collect())
+                    .collect())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Running mean of the samples of one device in a time bucket
+struct Mean {
+    device: String,
+    session: Option<String>,
+    dims: (usize, usize, usize),
+    count: usize,
+    x: f64,
+    y: f64,
+    amplitude: Vec<Vec<Vec<f64>>>,
+}
+
+impl Mean {
+    fn new(s: &Sample) -> Self {
+        Mean {
+            device: s.device.clone(),
+            session: s.session.clone(),
+            dims: s.dims(),
+            count: 1,
+            x: s.x,
+            y: s.y,
+            amplitude: s.amplitude(),
+        }
+    }
+
+    /// Samples whose dimensions differ from the first one are left out
+    fn add(&mut self, s: &Sample) {
+        if s.dims() != self.dims {
+            return;
+        }
+
+        for (acc, a) in self.amplitude.iter_mut().flatten().flatten().zip(s.amplitude().iter().flatten().flatten()) {
+            *acc += a;
+        }
+        self.x += s.x;
+        self.y += s.y;
+        self.count += 1;
+    }
+
+    fn point(mut self, bucket: DateTime<Utc>, fields: &[Field]) -> Point {
+        let n = self.count as f64;
+        self.amplitude.iter_mut().flatten().flatten().for_each(|a| *a /= n);
+
+        let has = |f| fields.contains(&f);
+        Point {
+            date: bucket,
+            device: self.device,
+            session: self.session,
+            count: Some(self.count),
+            x: if has(Field::Position) { Some(self.x / n) } else { None },
+            y: if has(Field::Position) { Some(self.y / n) } else { None },
+            meta: None,
+            amplitude: if has(Field::Amplitude) { Some(self.amplitude) } else { None },
+            phase: None,
+            csi: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Page {
+    pub samples: Vec<Point>,
+    /// Pass as `cursor` to get the next page, `null` on the last page
+    pub next: Option<String>,
+}
+
+/// Reads the matches of a filter from a cursor on, `CHUNK` samples at a
+/// time, so a page never holds more than one chunk of raw samples
+struct Reader<'s, 'f> {
+    storage: &'s mut dyn Storage,
+    filter: Filter<'f>,
+    /// After the last sample read from the storage
+    read: Option<Cursor>,
+    /// After the last sample taken into the page
+    at: Option<Cursor>,
+    chunk: std::vec::IntoIter<Sample>,
+    scanned: usize,
+    exhausted: bool,
+}
+
+impl<'s, 'f> Reader<'s, 'f> {
+    fn new(storage: &'s mut dyn Storage, filter: Filter<'f>, cursor: Option<Cursor>) -> Self {
+        Reader {
+            storage,
+            filter,
+            read: cursor,
+            at: cursor,
+            chunk: vec![].into_iter(),
+            scanned: 0,
+            exhausted: false,
+        }
+    }
+
+    /// The next match; `None` at the end, or once `MAX_SCANNED` samples
+    /// were read
+    fn next(&mut self) -> io::Result<Option<Sample>> {
+        if let Some(s) = self.chunk.next() {
+            return Ok(Some(s));
+        }
+        if self.exhausted || self.scanned >= MAX_SCANNED {
+            return Ok(None);
+        }
+
+        let mut filter = self.filter.clone();
+        let skip = match self.read {
+            Some(c) => {
+                filter.from = Some(c.date);
+                c.skip
+            }
+            None => 0,
+        };
+        let want = CHUNK.min(MAX_SCANNED - self.scanned);
+        let chunk = self.storage.query_page(&filter, skip, want)?;
+        self.exhausted = chunk.len() < want;
+        self.scanned += chunk.len();
+        for s in &chunk {
+            self.read = Some(Cursor::step(self.read, s.date));
+        }
+        self.chunk = chunk.into_iter();
+
+        Ok(self.chunk.next())
+    }
+
+    /// Whether `next` stopped at `MAX_SCANNED` rather than at the end
+    fn capped(&self) -> bool {
+        !self.exhausted && self.scanned >= MAX_SCANNED
+    }
+
+    /// Take a sample returned by `next` into the page
+    fn take(&mut self, s: &Sample) {
+        self.at = Some(Cursor::step(self.at, s.date));
+    }
+}
+
+fn bucket_start(date: DateTime<Utc>, bucket_us: i64) -> (i64, DateTime<Utc>) {
+    let micros = date.timestamp() * 1_000_000 + date.timestamp_subsec_micros() as i64;
+    let idx = micros.div_euclid(bucket_us);
+    let start = idx * bucket_us;
+
+    (idx, Utc.timestamp_opt(start.div_euclid(1_000_000), (start.rem_euclid(1_000_000) * 1000) as u32).unwrap())
+}
+
+/// Run a query against the storage
+pub fn run(storage: &mut dyn Storage, params: &Params) -> Result<Page, String> {
+    let fields = params.fields()?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+    let every = params.every.unwrap_or(1).max(1);
+    if every > MAX_SCANNED {
+        return Err(format!("every must be at most {}", MAX_SCANNED));
+    }
+    let cursor = match &params.cursor {
+        Some(c) => Some(c.parse::<Cursor>()?),
+        None => None,
+    };
+
+    let filter = Filter {
+        device: params.device.as_deref(),
+        session: params.session.as_deref(),
+        from: params.from,
+        to: params.to,
+    };
+    let mut reader = Reader::new(storage, filter, cursor);
+    let io_err = |e: io::Error| e.to_string();
+    let mut points = vec![];
+
+    let next = if let Some(ms) = params.average_ms {
+        let bucket_us = i64::try_from(ms.max(1).saturating_mul(1000)).unwrap_or(i64::MAX);
+        // the bucket being read, its means by device and the cursor before it
+        let mut open: Option<DateTime<Utc>> = None;
+        let mut open_idx = 0;
+        let mut means: BTreeMap<String, Mean> = BTreeMap::new();
+        let mut before = reader.at;
+
+        loop {
+            let s = match reader.next().map_err(io_err)? {
+                Some(s) => s,
+                // whole buckets only, the last one may continue on the next page
+                None if reader.capped() && !points.is_empty() => break before,
+                None => {
+                    if let Some(date) = open {
+                        points.extend(std::mem::take(&mut means).into_values().map(|m| m.point(date, &fields)));
+                    }
+                    break if reader.capped() { reader.at } else { None };
+                }
+            };
+
+            let (idx, date) = bucket_start(s.date, bucket_us);
+            if open.is_none() || idx != open_idx {
+                if let Some(done) = open.replace(date) {
+                    points.extend(std::mem::take(&mut means).into_values().map(|m| m.point(done, &fields)));
+                    before = reader.at;
+                }
+                open_idx = idx;
+                if points.len() >= limit {
+                    break before;
+                }
+            }
+
+            // a bucket holds a point per device; one that would not fit
+            // starts the next page, unless it is the first
+            let full = !points.is_empty() && points.len() + means.len() >= limit;
+            match means.get_mut(&s.device) {
+                Some(mean) => mean.add(&s),
+                None if full => break before,
+                None => {
+                    means.insert(s.device.clone(), Mean::new(&s));
+                }
+            }
+            reader.take(&s);
+        }
+    } else {
+        // every `every`-th sample of each device, counting from the page start
+        let mut skipping: BTreeMap<String, usize> = BTreeMap::new();
+
+        loop {
+            let s = match reader.next().map_err(io_err)? {
+                Some(s) => s,
+                None if reader.capped() => break reader.at,
+                None => break None,
+            };
+
+            let left = skipping.entry(s.device.clone()).or_insert(0);
+            if *left == 0 {
+                if points.len() == limit {
+                    break reader.at;
+                }
+                points.push(Point::new(&s, &fields));
+                *left = every;
+            }
+            *left -= 1;
+            reader.take(&s);
+        }
+    };
+
+    Ok(Page { samples: points, next: next.map(|c| c.to_string()) })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::store::{Retention, SampleStore};
+    use crate::testutil::sample;
+
+    fn store(samples: Vec<Sample>) -> SampleStore {
+        let mut store = SampleStore::new(Retention::default(), None);
+        for s in samples {
+            store.push(s).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let c = Cursor { date: "2020-06-01T10:00:00.123456Z".parse().unwrap(), skip: 3 };
+        assert_eq!(c.to_string().parse::<Cursor>().unwrap(), c);
+        assert!("nonsense".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn pages_split_equal_timestamps() {
+        let t0: DateTime<Utc> = "2020-06-01T10:00:00Z".parse().unwrap();
+        // five samples, three of them sharing a timestamp
+        let dates = [0, 1, 1, 1, 2];
+        let mut storage = store(dates.iter().enumerate()
+            .map(|(i, &d)| sample("a", t0 + Duration::seconds(d), i as isize))
+            .collect());
+
+        let mut params = Params { limit: Some(2), fields: Some("position".into()), ..Default::default() };
+        let mut seen = vec![];
+        loop {
+            let page = run(&mut storage, &params).unwrap();
+            seen.extend(page.samples.iter().map(|p| p.x.unwrap()));
+            match page.next {
+                Some(next) => params.cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn decimate_and_average() {
+        let t0: DateTime<Utc> = "2020-06-01T10:00:00Z".parse().unwrap();
+        let mut storage = store((0..10)
+            .map(|i| sample("a", t0 + Duration::milliseconds(i * 100), i as isize))
+            .collect());
+
+        let every = Params { every: Some(3), ..Default::default() };
+        let page = run(&mut storage, &every).unwrap();
+        assert_eq!(page.samples.iter().map(|p| p.x.unwrap()).collect::<Vec<_>>(), vec![0.0, 3.0, 6.0, 9.0]);
+        assert!(page.next.is_none());
+
+        // 500 ms buckets: 0..=4 and 5..=9
+        let average = Params { average_ms: Some(500), ..Default::default() };
+        let page = run(&mut storage, &average).unwrap();
+        assert_eq!(page.samples.len(), 2);
+        assert_eq!(page.samples[0].count, Some(5));
+        assert_eq!(page.samples[1].date, t0 + Duration::milliseconds(500));
+        // |7 - 7i|
+        let mean = &page.samples[1].amplitude.as_ref().unwrap()[0][0];
+        assert_eq!(mean.len(), 3);
+        assert!(mean.iter().all(|a| (a - 7.0 * std::f64::consts::SQRT_2).abs() < 1e-9));
+        assert!(page.next.is_none());
+
+        assert!(run(&mut storage, &Params { every: Some(MAX_SCANNED + 1), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn read_in_chunks() {
+        let t0: DateTime<Utc> = "2020-06-01T10:00:00Z".parse().unwrap();
+        let n = CHUNK * 2 + CHUNK / 2;
+        let mut storage = store((0..n)
+            .map(|i| sample("a", t0 + Duration::milliseconds(i as i64), i as isize))
+            .collect());
+
+        let every = Params { every: Some(CHUNK), fields: Some("position".into()), ..Default::default() };
+        let page = run(&mut storage, &every).unwrap();
+        assert_eq!(page.samples.iter().map(|p| p.x.unwrap()).collect::<Vec<_>>(), vec![0.0, 1000.0, 2000.0]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn limit_counts_points() {
+        let t0: DateTime<Utc> = "2020-06-01T10:00:00Z".parse().unwrap();
+        // two devices in each of three buckets
+        let mut storage = store((0..6)
+            .map(|i| sample(if i % 2 == 0 { "a" } else { "b" }, t0 + Duration::milliseconds(i * 500), i as isize))
+            .collect());
+
+        let mut params = Params { average_ms: Some(1000), limit: Some(3), ..Default::default() };
+        let mut pages = vec![];
+        loop {
+            let page = run(&mut storage, &params).unwrap();
+            assert!(page.samples.len() <= 3);
+            pages.push(page.samples.iter().map(|p| (p.date, p.device.clone())).collect::<Vec<_>>());
+            match page.next {
+                Some(next) => params.cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[1], vec![(t0 + Duration::seconds(1), "a".to_string()), (t0 + Duration::seconds(1), "b".to_string())]);
+    }
+}
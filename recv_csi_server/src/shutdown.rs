@@ -0,0 +1,30 @@
+use futures::future::{self, Either};
+
+/// Resolve once the process receives SIGINT (Ctrl-C) or SIGTERM
+#[cfg(unix)]
+pub async fn wait_for_signal() {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            eprintln!("Cannot listen for SIGTERM: {}", e);
+            let _ = actix_rt::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    let ctrl_c = Box::pin(actix_rt::signal::ctrl_c());
+    let term = Box::pin(sigterm.recv());
+
+    match future::select(ctrl_c, term).await {
+        Either::Left(_) => println!("SIGINT received"),
+        Either::Right(_) => println!("SIGTERM received"),
+    };
+}
+
+#[cfg(not(unix))]
+pub async fn wait_for_signal() {
+    let _ = actix_rt::signal::ctrl_c().await;
+    println!("Ctrl-C received");
+}
@@ -0,0 +1,121 @@
+//! What the server is doing, for `/status`
+
+use std::collections::BTreeMap;
+use std::io;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::survey::Summary as SurveySummary;
+use crate::types::{Annotation, CSIData, Session, WriteConfig};
+
+/// A device is reported as connected if its last frame is at most this old
+pub const CONNECTED_SECS: i64 = 10;
+
+/// Antenna and channel configuration of a device's latest frame
+#[derive(Clone, Debug, Serialize)]
+pub struct RadioConfig {
+    pub nr: u8,
+    pub nc: u8,
+    pub num_tones: u8,
+    pub channel: u16,
+    /// 0 for 20 MHz, 1 for 40 MHz
+    pub chan_bw: u8,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceStatus {
+    pub connected: bool,
+    pub last_seen: DateTime<Utc>,
+    /// Seconds since the last frame
+    pub age: f64,
+    pub frames: usize,
+    /// Average frame rate since the first frame
+    pub rate: f64,
+    pub radio: Option<RadioConfig>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StorageStatus {
+    pub backend: &'static str,
+    /// Samples held in memory, not yet in the backend's files or database
+    pub in_memory: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub started: DateTime<Utc>,
+    pub uptime: f64,
+    /// False once shutdown started
+    pub accepting: bool,
+    pub devices: BTreeMap<String, DeviceStatus>,
+    /// Where a frame received now is placed, see `CSIData::position`
+    pub position: Position,
+    /// The running session
+    pub session: Option<Session>,
+    /// Progress of `--survey`, `/survey` has the details
+    pub survey: Option<SurveySummary>,
+    /// Annotations covering the present: started and not ended
+    pub annotations: Vec<Annotation>,
+    pub storage: StorageStatus,
+    /// `None` with `--no-output`
+    pub output: Option<WriteConfig>,
+    pub stream_subscribers: usize,
+}
+
+fn seconds(d: chrono::Duration) -> f64 {
+    d.num_milliseconds() as f64 / 1000.0
+}
+
+impl Status {
+    pub fn collect(x: &mut CSIData, now: DateTime<Utc>) -> io::Result<Self> {
+        let mut devices = BTreeMap::new();
+        for (id, stats) in &x.devices {
+            let radio = x.storage.latest(Some(id))?.map(|s| RadioConfig {
+                nr: s.status.nr,
+                nc: s.status.nc,
+                num_tones: s.status.num_tones,
+                channel: s.status.channel,
+                chan_bw: s.status.chanBW,
+            });
+            let age = now - stats.last_seen;
+
+            devices.insert(id.clone(), DeviceStatus {
+                connected: age.num_seconds() <= CONNECTED_SECS,
+                last_seen: stats.last_seen,
+                age: seconds(age),
+                frames: stats.frames,
+                rate: stats.rate(),
+                radio,
+            });
+        }
+
+        Ok(Status {
+            started: x.started,
+            uptime: seconds(now - x.started),
+            accepting: x.accepting,
+            devices,
+            position: {
+                let (x, y) = x.position();
+                Position { x, y }
+            },
+            session: x.session.clone(),
+            survey: x.survey.as_ref().map(|s| s.summary()),
+            annotations: x.storage.annotations()?.into_iter()
+                .filter(|a| a.start <= now && a.end.map_or(true, |end| end > now))
+                .collect(),
+            storage: StorageStatus {
+                backend: x.storage.name(),
+                in_memory: x.storage.in_memory(),
+            },
+            output: x.c.clone(),
+            stream_subscribers: x.hub.subscribers(),
+        })
+    }
+}
@@ -0,0 +1,485 @@
+//! Where accepted samples are kept and queried from
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::export::{self, Format};
+use crate::store::Filter;
+use crate::trajectory::Trajectory;
+use crate::types::{Annotation, DeviceStats, Sample, Session};
+
+/// A recording session and the samples it holds
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    pub stopped: Option<DateTime<Utc>>,
+    pub samples: usize,
+}
+
+pub fn session_exists(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("session '{}' already exists", name))
+}
+
+/// Sessions of the backends without a database; sample counts are taken
+/// from the samples themselves when listing
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SessionLog(Vec<SessionInfo>);
+
+impl SessionLog {
+    pub fn start(&mut self, session: &Session) -> io::Result<()> {
+        if self.0.iter().any(|s| s.session.name == session.name) {
+            return Err(session_exists(&session.name));
+        }
+
+        self.0.push(SessionInfo {
+            session: session.clone(),
+            stopped: None,
+            samples: 0,
+        });
+        Ok(())
+    }
+
+    pub fn stop(&mut self, name: &str, date: DateTime<Utc>) {
+        for s in self.0.iter_mut().filter(|s| s.session.name == name && s.stopped.is_none()) {
+            s.stopped = Some(date);
+        }
+    }
+
+    pub fn list(&self, counts: &BTreeMap<String, usize>) -> Vec<SessionInfo> {
+        self.0.iter()
+            .map(|s| SessionInfo {
+                samples: counts.get(&s.session.name).copied().unwrap_or(0),
+                ..s.clone()
+            })
+            .collect()
+    }
+}
+
+pub fn no_annotation(id: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no annotation {}", id))
+}
+
+/// Annotations of the backends without a database
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AnnotationLog {
+    /// Last id handed out
+    last_id: u64,
+    annotations: Vec<Annotation>,
+}
+
+impl AnnotationLog {
+    pub fn add(&mut self, mut annotation: Annotation) -> Annotation {
+        self.last_id += 1;
+        annotation.id = self.last_id;
+        self.annotations.push(annotation.clone());
+
+        annotation
+    }
+
+    pub fn end(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation> {
+        let a = self.annotations.iter_mut()
+            .find(|a| a.id == id)
+            .ok_or_else(|| no_annotation(id))?;
+        if end < a.start {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "end is before start"));
+        }
+        a.end = Some(end);
+
+        Ok(a.clone())
+    }
+
+    pub fn delete(&mut self, id: u64) -> io::Result<()> {
+        let before = self.annotations.len();
+        self.annotations.retain(|a| a.id != id);
+        if self.annotations.len() == before {
+            return Err(no_annotation(id));
+        }
+
+        Ok(())
+    }
+
+    /// Every annotation by start
+    pub fn list(&self) -> Vec<Annotation> {
+        let mut res = self.annotations.clone();
+        res.sort_by_key(|a| (a.start, a.id));
+        res
+    }
+}
+
+/// Count samples per session
+pub fn count_sessions<'a, I: IntoIterator<Item = &'a Sample>>(counts: &mut BTreeMap<String, usize>, samples: I) {
+    for name in samples.into_iter().filter_map(|s| s.session.as_ref()) {
+        *counts.entry(name.clone()).or_insert(0) += 1;
+    }
+}
+
+/// A storage backend.
+///
+/// Handlers only talk to this trait, so backends can be swapped from the
+/// command line. Every implementation must pass `tests::conformance`.
+pub trait Storage: Send {
+    /// Backend name as given to `--storage`
+    fn name(&self) -> &'static str;
+
+    fn append(&mut self, sample: Sample) -> io::Result<()>;
+
+    /// Matching samples, oldest first
+    fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>>;
+
+    /// One page of `query`: skip the first `skip` matches, return at most
+    /// `limit`. Backends should override this to avoid loading every match.
+    fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        Ok(self.query(filter)?.into_iter().skip(skip).take(limit).collect())
+    }
+
+    /// The newest `limit` matches of `query`, oldest first
+    fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>>;
+
+    /// Capture times of the matches of `query`, oldest first, for counting
+    /// samples without holding all of them in memory
+    fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>>;
+
+    /// The most recent sample of a device, or of any device if `None`
+    fn latest(&mut self, device: Option<&str>) -> io::Result<Option<Sample>>;
+
+    /// Record the start of a session. Fails with `AlreadyExists` if the
+    /// name was used before.
+    fn start_session(&mut self, session: &Session) -> io::Result<()>;
+
+    fn stop_session(&mut self, name: &str, date: DateTime<Utc>) -> io::Result<()>;
+
+    /// Every session, oldest first
+    fn sessions(&mut self) -> io::Result<Vec<SessionInfo>>;
+
+    /// Store an annotation and return it with its id
+    fn add_annotation(&mut self, annotation: Annotation) -> io::Result<Annotation>;
+
+    /// Close an open annotation. Fails with `NotFound` for an unknown id.
+    fn end_annotation(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation>;
+
+    fn delete_annotation(&mut self, id: u64) -> io::Result<()>;
+
+    /// Every annotation, by start
+    fn annotations(&mut self) -> io::Result<Vec<Annotation>>;
+
+    /// Replace the ground-truth trajectory of a session
+    fn set_trajectory(&mut self, session: &str, trajectory: &Trajectory) -> io::Result<()>;
+
+    fn trajectory(&mut self, session: &str) -> io::Result<Option<Trajectory>>;
+
+    /// Matching samples with the annotations and their sessions'
+    /// trajectories, to be exported later
+    fn snapshot(&mut self, filter: &Filter) -> io::Result<export::Snapshot> {
+        let samples = self.query(filter)?;
+        let annotations = self.annotations()?;
+        let mut trajectories = BTreeMap::new();
+        for name in samples.iter().filter_map(|s| s.session.as_ref()) {
+            if !trajectories.contains_key(name) {
+                if let Some(t) = self.trajectory(name)? {
+                    trajectories.insert(name.clone(), t);
+                }
+            }
+        }
+
+        Ok(export::Snapshot { samples, annotations, trajectories })
+    }
+
+    /// Write matching samples in `format` with their annotations and the
+    /// poses of their sessions' trajectories, returns how many were written
+    fn export(&mut self, format: Format, opts: &export::Options, filter: &Filter, w: &mut (dyn Write + Send)) -> io::Result<usize> {
+        self.snapshot(filter)?.write(format, opts, w)
+    }
+
+    /// Write buffered samples
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Make everything appended so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Persist every sample before shutdown
+    fn close(&mut self) -> io::Result<()>;
+
+    /// Whether stored samples outlive the process
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    /// Samples currently held in memory
+    fn in_memory(&self) -> usize {
+        0
+    }
+
+    fn add_position(&mut self, _date: DateTime<Utc>, _x: f64, _y: f64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The last position recorded by a previous run
+    fn last_position(&mut self) -> io::Result<Option<(f64, f64)>> {
+        Ok(None)
+    }
+
+    /// Device statistics recorded by previous runs
+    fn devices(&mut self) -> io::Result<BTreeMap<String, DeviceStats>> {
+        Ok(BTreeMap::new())
+    }
+}
+
+/// Storage backends selectable with `--storage`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// In-memory window, optionally spilling evicted samples to `--spill-dir`
+    Memory,
+    /// Every sample in rotating segment files in `--spill-dir`
+    File,
+    /// SQLite database at `--db`, needs the `sqlite` feature
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Backend::Memory),
+            "file" => Ok(Backend::File),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(format!("unknown storage '{}', expected memory, file or sqlite", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::store::{Retention, SampleStore, Spill};
+    use crate::testutil::{sample, temp_dir};
+    use crate::types::Receive;
+
+    fn annotation(start: DateTime<Utc>) -> Annotation {
+        Annotation {
+            id: 0,
+            start,
+            end: None,
+            device: None,
+            session: None,
+            x: None,
+            y: None,
+            z: None,
+            heading: None,
+            activity: None,
+            subject: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Behaviour every backend has to provide
+    fn conformance(mut storage: Box<dyn Storage>) {
+        let t0: DateTime<Utc> = "2020-06-01T10:00:00Z".parse().unwrap();
+        let at = |s: i64| t0 + Duration::seconds(s);
+
+        assert!(storage.latest(None).unwrap().is_none());
+        assert!(storage.query(&Filter::default()).unwrap().is_empty());
+
+        for i in 0..10 {
+            let device = if i % 2 == 0 { "a" } else { "b" };
+            storage.append(sample(device, at(i), i as isize)).unwrap();
+        }
+
+        // round trip
+        let all = storage.query(&Filter::default()).unwrap();
+        assert_eq!(all.len(), 10);
+        assert_eq!(all[3].device, "b");
+        assert_eq!(all[3].date, at(3));
+        assert_eq!(all[3].csi, sample("b", at(3), 3).csi);
+        assert_eq!(all[3].status.tstamp, 3);
+        assert_eq!(all[3].payload, vec![1, 2, 3]);
+        assert_eq!((all[3].x, all[3].y), (3.0, -2.5));
+
+        // oldest first
+        assert!(all.windows(2).all(|w| w[0].date <= w[1].date));
+
+        // device and inclusive time range
+        let range = storage.query(&Filter {
+            device: Some("a"),
+            from: Some(at(2)),
+            to: Some(at(6)),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(range.iter().map(|s| s.date).collect::<Vec<_>>(), vec![at(2), at(4), at(6)]);
+
+        // pages
+        let page = storage.query_page(&Filter::default(), 4, 3).unwrap();
+        assert_eq!(page.iter().map(|s| s.date).collect::<Vec<_>>(), vec![at(4), at(5), at(6)]);
+        assert_eq!(storage.query_page(&Filter::default(), 8, 5).unwrap().len(), 2);
+
+        // the newest matches, oldest first
+        let dates = |samples: Vec<Sample>| samples.iter().map(|s| s.date).collect::<Vec<_>>();
+        assert_eq!(dates(storage.query_last(&Filter::default(), 3).unwrap()), vec![at(7), at(8), at(9)]);
+        assert_eq!(dates(storage.query_last(&Filter { device: Some("a"), ..Default::default() }, 2).unwrap()), vec![at(6), at(8)]);
+        assert_eq!(storage.query_last(&Filter::default(), 50).unwrap().len(), 10);
+
+        assert_eq!(storage.latest(None).unwrap().unwrap().date, at(9));
+        assert_eq!(storage.latest(Some("a")).unwrap().unwrap().date, at(8));
+        assert!(storage.latest(Some("c")).unwrap().is_none());
+
+        // export goes through the same query
+        let mut out = vec![];
+        let n = storage.export(Format::Csv, &export::Options::default(), &Filter {
+            device: Some("b"),
+            ..Default::default()
+        }, &mut out).unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 6);
+
+        // sessions
+        let session = Session {
+            name: "walk".to_string(),
+            started: at(10),
+            present: true,
+            position: Receive::Predefined(3.0, 4.0),
+        };
+        storage.start_session(&session).unwrap();
+        assert_eq!(storage.start_session(&session).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        for i in 10..13 {
+            let mut s = sample("a", at(i), i as isize);
+            s.session = Some("walk".to_string());
+            storage.append(s).unwrap();
+        }
+        storage.stop_session("walk", at(13)).unwrap();
+
+        let sessions = storage.sessions().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session, session);
+        assert_eq!(sessions[0].stopped, Some(at(13)));
+        assert_eq!(sessions[0].samples, 3);
+
+        let walk = storage.query(&Filter { session: Some("walk"), ..Default::default() }).unwrap();
+        assert_eq!(walk.iter().map(|s| s.date).collect::<Vec<_>>(), vec![at(10), at(11), at(12)]);
+        assert_eq!(walk[0].session.as_deref(), Some("walk"));
+        assert!(storage.query(&Filter { session: Some("other"), ..Default::default() }).unwrap().is_empty());
+
+        // annotations
+        let mut tags = BTreeMap::new();
+        tags.insert("shoes".to_string(), "none".to_string());
+        let walking = storage.add_annotation(Annotation {
+            session: Some("walk".to_string()),
+            x: Some(3.0),
+            y: Some(4.0),
+            z: Some(1.2),
+            activity: Some("walking".to_string()),
+            subject: Some("p1".to_string()),
+            tags,
+            ..annotation(at(10))
+        }).unwrap();
+        let typo = storage.add_annotation(annotation(at(0))).unwrap();
+        assert_ne!(walking.id, typo.id);
+        storage.delete_annotation(typo.id).unwrap();
+        assert_eq!(storage.delete_annotation(typo.id).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.end_annotation(typo.id, at(1)).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.end_annotation(walking.id, at(9)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let walking = storage.end_annotation(walking.id, at(12)).unwrap();
+        assert_eq!(walking.end, Some(at(12)));
+        assert_eq!(storage.annotations().unwrap(), vec![walking]);
+
+        // joined onto the samples it covers, the end is exclusive
+        let mut out = vec![];
+        storage.export(Format::Csv, &export::Options::default(), &Filter {
+            session: Some("walk"),
+            ..Default::default()
+        }, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].ends_with(",activity,subject,pose_x,pose_y,pose_z,heading,tags"));
+        assert!(lines[1].ends_with(",walking,p1,3,4,1.2,,\"{\"\"shoes\"\":\"\"none\"\"}\""));
+        assert!(lines[3].ends_with(",,,,,,,"));
+
+        // trajectories, on a clock one second behind
+        assert!(storage.trajectory("walk").unwrap().is_none());
+        let trajectory = Trajectory::parse(
+            "t,x,y\n1591005609,0,0\n1591005611,4,2\n", crate::trajectory::Format::Csv, 1000, None,
+        ).unwrap();
+        storage.set_trajectory("walk", &trajectory).unwrap();
+        assert_eq!(storage.trajectory("walk").unwrap(), Some(trajectory.clone()));
+        let dates = storage.dates(&Filter {
+            session: Some("walk"),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(dates, walk.iter().map(|s| s.date).collect::<Vec<_>>());
+        assert_eq!(trajectory.report(&dates).interpolated, 3);
+
+        // the trajectory's pose replaces the annotation's as a whole
+        let mut out = vec![];
+        storage.export(Format::Csv, &export::Options::default(), &Filter {
+            session: Some("walk"),
+            ..Default::default()
+        }, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[1].ends_with(",walking,p1,0,0,,,\"{\"\"shoes\"\":\"\"none\"\"}\""));
+        assert!(lines[2].contains(",walking,p1,2,1,,,"));
+        assert!(lines[3].ends_with(",,,4,2,,,"));
+
+        storage.flush().unwrap();
+        storage.sync().unwrap();
+        storage.close().unwrap();
+    }
+
+    #[test]
+    fn memory() {
+        conformance(Box::new(SampleStore::new(Retention::default(), None)));
+    }
+
+    #[test]
+    fn memory_with_spill() {
+        let dir = temp_dir("memory_spill");
+        let retention = Retention {
+            max_samples: Some(3),
+            max_age: None,
+        };
+        conformance(Box::new(SampleStore::new(retention, Some(Spill::open(&dir, 4).unwrap()))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file() {
+        let dir = temp_dir("file");
+        conformance(Box::new(Spill::open(&dir, 4).unwrap()));
+
+        // segments, sessions, annotations and trajectories are picked up
+        // again after a restart
+        let mut reopened = Spill::open(&dir, 4).unwrap();
+        assert_eq!(reopened.query(&Filter::default()).unwrap().len(), 13);
+        assert_eq!(reopened.latest(Some("b")).unwrap().unwrap().status.tstamp, 9);
+        assert_eq!(reopened.sessions().unwrap()[0].samples, 3);
+        assert_eq!(reopened.annotations().unwrap()[0].activity.as_deref(), Some("walking"));
+        assert_eq!(reopened.trajectory("walk").unwrap().unwrap().offset_ms, 1000);
+        // ids are not reused
+        assert_eq!(reopened.add_annotation(annotation(Utc::now())).unwrap().id, 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite() {
+        let dir = temp_dir("sqlite");
+        conformance(Box::new(crate::db::Db::open(dir.join("csi.db"), 3).unwrap()));
+
+        let mut reopened = crate::db::Db::open(dir.join("csi.db"), 3).unwrap();
+        assert_eq!(reopened.query(&Filter::default()).unwrap().len(), 13);
+        // values beyond the blob's i16 are refused, not truncated
+        let mut loud = sample("a", reopened.query(&Filter::default()).unwrap()[0].date, 1);
+        loud.csi[1][0][2].re = 40_000;
+        assert_eq!(reopened.append(loud).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        reopened.flush().unwrap();
+        assert_eq!(reopened.query(&Filter::default()).unwrap().len(), 13);
+        assert_eq!(reopened.devices().unwrap()["a"].frames, 8);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::storage::{count_sessions, AnnotationLog, SessionInfo, SessionLog, Storage};
+use crate::trajectory::Trajectory;
+use crate::types::{Annotation, Sample, Session};
+
+/// How many samples are kept in memory
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    /// Keep at most this many of the most recent samples
+    pub max_samples: Option<usize>,
+    /// Keep only samples younger than this, relative to the newest one
+    pub max_age: Option<Duration>,
+}
+
+/// Samples matching an optional device, session and time range
+#[derive(Clone, Debug, Default)]
+pub struct Filter<'a> {
+    pub device: Option<&'a str>,
+    pub session: Option<&'a str>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl<'a> Filter<'a> {
+    pub fn matches(&self, s: &Sample) -> bool {
+        self.device.map_or(true, |d| s.device == d)
+            && self.session.map_or(true, |name| s.session.as_deref() == Some(name))
+            && self.from.map_or(true, |from| s.date >= from)
+            && self.to.map_or(true, |to| s.date <= to)
+    }
+
+    /// Whether a segment spanning `first..=last` may contain matching samples
+    fn overlaps(&self, first: DateTime<Utc>, last: DateTime<Utc>) -> bool {
+        self.from.map_or(true, |from| last >= from)
+            && self.to.map_or(true, |to| first <= to)
+    }
+}
+
+/// In-memory ring buffer of the most recent samples.
+///
+/// Samples that fall out of the retention window are moved to the spill
+/// directory if one is configured, and dropped otherwise.
+pub struct SampleStore {
+    recent: VecDeque<Sample>,
+    retention: Retention,
+    spill: Option<Spill>,
+    /// Sessions, annotations and trajectories when there is no spill
+    /// directory to keep them in
+    sessions: SessionLog,
+    annotations: AnnotationLog,
+    trajectories: BTreeMap<String, Trajectory>,
+}
+
+impl SampleStore {
+    pub fn new(retention: Retention, spill: Option<Spill>) -> Self {
+        Self {
+            recent: VecDeque::new(),
+            retention,
+            spill,
+            sessions: SessionLog::default(),
+            annotations: AnnotationLog::default(),
+            trajectories: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: Sample) -> io::Result<()> {
+        self.recent.push_back(sample);
+        self.evict()
+    }
+
+    fn evict(&mut self) -> io::Result<()> {
+        if let Some(max) = self.retention.max_samples {
+            while self.recent.len() > max {
+                self.spill_front()?;
+            }
+        }
+
+        if let (Some(max_age), Some(newest)) = (self.retention.max_age, self.recent.back()) {
+            let oldest_kept = newest.date - max_age;
+            while self.recent.front().map_or(false, |s| s.date < oldest_kept) {
+                self.spill_front()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn spill_front(&mut self) -> io::Result<()> {
+        if let Some(s) = self.recent.pop_front() {
+            if let Some(spill) = self.spill.as_mut() {
+                spill.write(&s)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make spilled samples durable
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Move every in-memory sample to the spill directory, if there is one
+    pub fn spill_all(&mut self) -> io::Result<()> {
+        if self.spill.is_some() {
+            while !self.recent.is_empty() {
+                self.spill_front()?;
+            }
+        }
+
+        self.sync()
+    }
+
+    /// The most recent sample of a device, or of any device if `None`
+    pub fn last(&self, device: Option<&str>) -> Option<&Sample> {
+        self.recent.iter()
+            .rev()
+            .find(|s| device.map_or(true, |d| s.device == d))
+    }
+
+    /// Matching samples from disk and memory, oldest first
+    pub fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        self.query_page(filter, 0, usize::MAX)
+    }
+
+    /// The newest `limit` matches of `query`, oldest first. Spilled
+    /// segments are only read if memory holds fewer.
+    pub fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        let mut res: Vec<Sample> = self.recent.iter()
+            .rev()
+            .filter(|s| filter.matches(s))
+            .take(limit)
+            .cloned()
+            .collect();
+        res.reverse();
+
+        if let Some(spill) = self.spill.as_mut() {
+            if res.len() < limit {
+                let mut spilled = spill.query_last(filter, limit - res.len())?;
+                spilled.append(&mut res);
+                res = spilled;
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Capture times of `query`; spilled segments are read one at a time
+    pub fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        let mut dates = match self.spill.as_mut() {
+            Some(spill) => spill.dates(filter)?,
+            None => vec![],
+        };
+        dates.extend(self.recent.iter().filter(|s| filter.matches(s)).map(|s| s.date));
+
+        Ok(dates)
+    }
+
+    /// Like `query`, skipping `skip` samples and returning at most `limit`.
+    ///
+    /// Spilled segments are only read until the page is full.
+    pub fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        let spilled: Box<dyn Iterator<Item = io::Result<Sample>>> = match self.spill.as_mut() {
+            Some(spill) => Box::new(spill.scan(filter)?),
+            None => Box::new(std::iter::empty()),
+        };
+        let recent = self.recent.iter()
+            .filter(|s| filter.matches(s))
+            .cloned()
+            .map(Ok);
+
+        spilled.chain(recent)
+            .skip(skip)
+            .take(limit)
+            .collect()
+    }
+}
+
+/// A file of spilled samples and the time span it covers
+struct Segment {
+    path: PathBuf,
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    count: usize,
+    /// Samples per session
+    sessions: BTreeMap<String, usize>,
+}
+
+/// Disk-backed storage for samples evicted from memory.
+///
+/// Samples are appended to bincode segment files of at most
+/// `segment_samples` entries; queries only read segments overlapping the
+/// requested time range. Sessions are kept in `sessions.json`, annotations
+/// in `annotations.json` and trajectories in `trajectories.json`.
+pub struct Spill {
+    dir: PathBuf,
+    segment_samples: usize,
+    segments: Vec<Segment>,
+    writer: Option<BufWriter<File>>,
+    /// Most recent sample of every device
+    latest: BTreeMap<String, Sample>,
+    sessions: SessionLog,
+    annotations: AnnotationLog,
+    trajectories: BTreeMap<String, Trajectory>,
+}
+
+impl Spill {
+    /// Open a spill directory, indexing segments left by previous runs
+    pub fn open<P: AsRef<Path>>(dir: P, segment_samples: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| Spill::segment_number(p).is_some())
+            .collect();
+        paths.sort();
+
+        let mut segments = vec![];
+        let mut latest = BTreeMap::new();
+        for path in paths {
+            let samples = read_segment(&path)?;
+            for s in &samples {
+                latest.insert(s.device.clone(), s.clone());
+            }
+            if let (Some(first), Some(last)) = (samples.first(), samples.last()) {
+                let mut sessions = BTreeMap::new();
+                count_sessions(&mut sessions, &samples);
+
+                segments.push(Segment {
+                    first: first.date,
+                    last: last.date,
+                    count: samples.len(),
+                    sessions,
+                    path,
+                });
+            }
+        }
+
+        let sessions = load_json(&dir.join(SESSIONS_FILE))?;
+        let annotations = load_json(&dir.join(ANNOTATIONS_FILE))?;
+        let trajectories = load_json(&dir.join(TRAJECTORIES_FILE))?;
+
+        Ok(Self {
+            dir,
+            segment_samples: segment_samples.max(1),
+            segments,
+            writer: None,
+            latest,
+            sessions,
+            annotations,
+            trajectories,
+        })
+    }
+
+    /// Replace a file of the spill directory in one step
+    fn save_json<T: Serialize>(&self, name: &str, value: &T) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, self.dir.join(name))
+    }
+
+    fn start_session(&mut self, session: &Session) -> io::Result<()> {
+        self.sessions.start(session)?;
+        self.save_json(SESSIONS_FILE, &self.sessions)
+    }
+
+    fn stop_session(&mut self, name: &str, date: DateTime<Utc>) -> io::Result<()> {
+        self.sessions.stop(name, date);
+        self.save_json(SESSIONS_FILE, &self.sessions)
+    }
+
+    fn add_annotation(&mut self, annotation: Annotation) -> io::Result<Annotation> {
+        let annotation = self.annotations.add(annotation);
+        self.save_json(ANNOTATIONS_FILE, &self.annotations)?;
+
+        Ok(annotation)
+    }
+
+    fn end_annotation(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation> {
+        let annotation = self.annotations.end(id, end)?;
+        self.save_json(ANNOTATIONS_FILE, &self.annotations)?;
+
+        Ok(annotation)
+    }
+
+    fn delete_annotation(&mut self, id: u64) -> io::Result<()> {
+        self.annotations.delete(id)?;
+        self.save_json(ANNOTATIONS_FILE, &self.annotations)
+    }
+
+    fn set_trajectory(&mut self, session: &str, trajectory: &Trajectory) -> io::Result<()> {
+        self.trajectories.insert(session.to_string(), trajectory.clone());
+        self.save_json(TRAJECTORIES_FILE, &self.trajectories)
+    }
+
+    /// Spilled samples per session
+    fn session_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for (name, n) in self.segments.iter().flat_map(|seg| &seg.sessions) {
+            *counts.entry(name.clone()).or_insert(0) += n;
+        }
+        counts
+    }
+
+    fn segment_number(path: &Path) -> Option<usize> {
+        let name = path.file_name()?.to_str()?;
+        name.strip_prefix("spill_")?
+            .strip_suffix(".bin")?
+            .parse()
+            .ok()
+    }
+
+    fn write(&mut self, s: &Sample) -> io::Result<()> {
+        let full = self.segments.last()
+            .map_or(true, |seg| seg.count >= self.segment_samples);
+
+        if self.writer.is_none() || full {
+            if let Some(mut w) = self.writer.take() {
+                w.flush()?;
+            }
+
+            let next = self.segments.last()
+                .and_then(|seg| Spill::segment_number(&seg.path))
+                .map_or(0, |n| n + 1);
+            let path = self.dir.join(format!("spill_{:08}.bin", next));
+            // a file with this number holds no samples, or it would be indexed
+            let file = OpenOptions::new().create(true).write(true).truncate(true).open(&path)?;
+
+            self.writer = Some(BufWriter::new(file));
+            self.segments.push(Segment {
+                path,
+                first: s.date,
+                last: s.date,
+                count: 0,
+                sessions: BTreeMap::new(),
+            });
+        }
+
+        let w = self.writer.as_mut().unwrap();
+        bincode::serialize_into(w, s)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let seg = self.segments.last_mut().unwrap();
+        seg.last = s.date;
+        seg.count += 1;
+        count_sessions(&mut seg.sessions, Some(s));
+
+        self.latest.insert(s.device.clone(), s.clone());
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(w) => {
+                w.flush()?;
+                w.get_ref().sync_data()
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        self.scan(filter)?.collect()
+    }
+
+    /// The newest `limit` matches of `query`, reading segments from the
+    /// newest back until there are enough
+    pub fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        self.flush()?;
+
+        let mut res = vec![];
+        for seg in self.segments.iter().rev().filter(|seg| filter.overlaps(seg.first, seg.last)) {
+            if res.len() >= limit {
+                break;
+            }
+            let samples = read_segment(&seg.path)?;
+            let wanted = limit - res.len();
+            res.extend(samples.into_iter().rev().filter(|s| filter.matches(s)).take(wanted));
+        }
+        res.reverse();
+
+        Ok(res)
+    }
+
+    /// Capture times of the matching samples, one segment in memory at a time
+    pub fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        self.flush()?;
+
+        let mut dates = vec![];
+        for seg in self.segments.iter().filter(|seg| filter.overlaps(seg.first, seg.last)) {
+            let samples = read_segment(&seg.path)?;
+            dates.extend(samples.iter().filter(|s| filter.matches(s)).map(|s| s.date));
+        }
+
+        Ok(dates)
+    }
+
+    /// Matching samples, reading each overlapping segment only when the
+    /// iterator reaches it
+    pub fn scan<'a>(&mut self, filter: &Filter<'a>) -> io::Result<impl Iterator<Item = io::Result<Sample>> + 'a> {
+        self.flush()?;
+
+        let filter = filter.clone();
+        let paths: Vec<PathBuf> = self.segments.iter()
+            .filter(|seg| filter.overlaps(seg.first, seg.last))
+            .map(|seg| seg.path.clone())
+            .collect();
+
+        Ok(paths.into_iter().flat_map(move |path| {
+            let samples: Vec<io::Result<Sample>> = match read_segment(&path) {
+                Ok(samples) => samples.into_iter()
+                    .filter(|s| filter.matches(s))
+                    .map(Ok)
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+            samples
+        }))
+    }
+}
+
+impl Storage for SampleStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn append(&mut self, sample: Sample) -> io::Result<()> {
+        self.push(sample)
+    }
+
+    fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        SampleStore::query(self, filter)
+    }
+
+    fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        SampleStore::query_page(self, filter, skip, limit)
+    }
+
+    fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        SampleStore::query_last(self, filter, limit)
+    }
+
+    fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        SampleStore::dates(self, filter)
+    }
+
+    fn latest(&mut self, device: Option<&str>) -> io::Result<Option<Sample>> {
+        if let Some(s) = self.last(device) {
+            return Ok(Some(s.clone()));
+        }
+
+        match self.spill.as_mut() {
+            Some(spill) => spill.latest(device),
+            None => Ok(None),
+        }
+    }
+
+    fn start_session(&mut self, session: &Session) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.start_session(session),
+            None => self.sessions.start(session),
+        }
+    }
+
+    fn stop_session(&mut self, name: &str, date: DateTime<Utc>) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.stop_session(name, date),
+            None => {
+                self.sessions.stop(name, date);
+                Ok(())
+            }
+        }
+    }
+
+    fn sessions(&mut self) -> io::Result<Vec<SessionInfo>> {
+        let (log, mut counts) = match &self.spill {
+            Some(spill) => (&spill.sessions, spill.session_counts()),
+            None => (&self.sessions, BTreeMap::new()),
+        };
+        count_sessions(&mut counts, &self.recent);
+
+        Ok(log.list(&counts))
+    }
+
+    fn add_annotation(&mut self, annotation: Annotation) -> io::Result<Annotation> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.add_annotation(annotation),
+            None => Ok(self.annotations.add(annotation)),
+        }
+    }
+
+    fn end_annotation(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.end_annotation(id, end),
+            None => self.annotations.end(id, end),
+        }
+    }
+
+    fn delete_annotation(&mut self, id: u64) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.delete_annotation(id),
+            None => self.annotations.delete(id),
+        }
+    }
+
+    fn annotations(&mut self) -> io::Result<Vec<Annotation>> {
+        Ok(match &self.spill {
+            Some(spill) => spill.annotations.list(),
+            None => self.annotations.list(),
+        })
+    }
+
+    fn set_trajectory(&mut self, session: &str, trajectory: &Trajectory) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.set_trajectory(session, trajectory),
+            None => {
+                self.trajectories.insert(session.to_string(), trajectory.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn trajectory(&mut self, session: &str) -> io::Result<Option<Trajectory>> {
+        let trajectories = match &self.spill {
+            Some(spill) => &spill.trajectories,
+            None => &self.trajectories,
+        };
+
+        Ok(trajectories.get(session).cloned())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some(spill) => spill.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        SampleStore::sync(self)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.spill_all()
+    }
+
+    fn persistent(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    fn in_memory(&self) -> usize {
+        self.recent.len()
+    }
+}
+
+/// Used on its own, the spill directory keeps every sample on disk
+impl Storage for Spill {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn append(&mut self, sample: Sample) -> io::Result<()> {
+        self.write(&sample)
+    }
+
+    fn query(&mut self, filter: &Filter) -> io::Result<Vec<Sample>> {
+        Spill::query(self, filter)
+    }
+
+    fn query_page(&mut self, filter: &Filter, skip: usize, limit: usize) -> io::Result<Vec<Sample>> {
+        self.scan(filter)?.skip(skip).take(limit).collect()
+    }
+
+    fn query_last(&mut self, filter: &Filter, limit: usize) -> io::Result<Vec<Sample>> {
+        Spill::query_last(self, filter, limit)
+    }
+
+    fn dates(&mut self, filter: &Filter) -> io::Result<Vec<DateTime<Utc>>> {
+        Spill::dates(self, filter)
+    }
+
+    fn latest(&mut self, device: Option<&str>) -> io::Result<Option<Sample>> {
+        let latest = match device {
+            Some(d) => self.latest.get(d),
+            None => self.latest.values().max_by_key(|s| s.date),
+        };
+
+        Ok(latest.cloned())
+    }
+
+    fn start_session(&mut self, session: &Session) -> io::Result<()> {
+        Spill::start_session(self, session)
+    }
+
+    fn stop_session(&mut self, name: &str, date: DateTime<Utc>) -> io::Result<()> {
+        Spill::stop_session(self, name, date)
+    }
+
+    fn sessions(&mut self) -> io::Result<Vec<SessionInfo>> {
+        Ok(self.sessions.list(&self.session_counts()))
+    }
+
+    fn add_annotation(&mut self, annotation: Annotation) -> io::Result<Annotation> {
+        Spill::add_annotation(self, annotation)
+    }
+
+    fn end_annotation(&mut self, id: u64, end: DateTime<Utc>) -> io::Result<Annotation> {
+        Spill::end_annotation(self, id, end)
+    }
+
+    fn delete_annotation(&mut self, id: u64) -> io::Result<()> {
+        Spill::delete_annotation(self, id)
+    }
+
+    fn annotations(&mut self) -> io::Result<Vec<Annotation>> {
+        Ok(self.annotations.list())
+    }
+
+    fn set_trajectory(&mut self, session: &str, trajectory: &Trajectory) -> io::Result<()> {
+        Spill::set_trajectory(self, session, trajectory)
+    }
+
+    fn trajectory(&mut self, session: &str) -> io::Result<Option<Trajectory>> {
+        Ok(self.trajectories.get(session).cloned())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Spill::flush(self)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Spill::sync(self)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Spill::sync(self)
+    }
+}
+
+/// Sessions, annotations and trajectories of a spill directory
+const SESSIONS_FILE: &str = "sessions.json";
+const ANNOTATIONS_FILE: &str = "annotations.json";
+const TRAJECTORIES_FILE: &str = "trajectories.json";
+
+/// A file written by `Spill::save_json`, the default if there is none yet
+fn load_json<T: Default + DeserializeOwned>(path: &Path) -> io::Result<T> {
+    match fs::read(path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_segment(path: &Path) -> io::Result<Vec<Sample>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut samples = vec![];
+
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(s) => samples.push(s),
+            Err(e) => match *e {
+                // end of the segment, or a record cut short by a crash
+                bincode::ErrorKind::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            },
+        }
+    }
+
+    Ok(samples)
+}
@@ -0,0 +1,326 @@
+//! Live CSI for `/ws` subscribers
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use bytes::BytesMut;
+use chrono::prelude::*;
+use csi_types::ser::{abs, phase, ComplexDef};
+use futures::channel::mpsc;
+use futures::{future, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::export::csv::Pairs;
+use crate::types::{Sample, View};
+
+/// Frames queued per subscriber before new ones are dropped
+pub const QUEUE: usize = 64;
+
+/// Query string of `/ws`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Params {
+    pub device: Option<String>,
+    /// `all` or a list of `rx:tx` pairs, e.g. `0:0,1:1`
+    pub pairs: Option<String>,
+    /// Inclusive subcarrier range `first-last`, or a single subcarrier
+    pub tones: Option<String>,
+    /// Send every N-th matching sample
+    pub every: Option<usize>,
+    #[serde(default)]
+    pub values: View,
+}
+
+/// What a subscriber asked for
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    pub device: Option<String>,
+    pub pairs: Pairs,
+    pub tones: Option<(usize, usize)>,
+    pub every: usize,
+    pub values: View,
+}
+
+fn parse_tones(s: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid subcarrier range '{}', expected first-last", s);
+    let mut it = s.splitn(2, '-');
+    let first: usize = it.next().and_then(|v| v.trim().parse().ok()).ok_or_else(invalid)?;
+    let last: usize = match it.next() {
+        Some(v) => v.trim().parse().map_err(|_| invalid())?,
+        None => first,
+    };
+
+    if first > last {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+impl Subscription {
+    pub fn new(p: &Params) -> Result<Self, String> {
+        Ok(Subscription {
+            device: p.device.clone(),
+            pairs: match &p.pairs {
+                Some(pairs) => pairs.parse()?,
+                None => Pairs::All,
+            },
+            tones: p.tones.as_deref().map(parse_tones).transpose()?,
+            every: p.every.unwrap_or(1).max(1),
+            values: p.values,
+        })
+    }
+
+    fn matches(&self, s: &Sample) -> bool {
+        self.device.as_ref().map_or(true, |d| *d == s.device)
+    }
+
+    /// Selected antenna pairs and tones of a sample
+    fn frame(&self, s: &Sample, dropped: usize) -> Frame {
+        let (nr, nc, tones) = s.dims();
+        let pairs: Vec<(usize, usize)> = match &self.pairs {
+            Pairs::All => (0..nr).flat_map(|rx| (0..nc).map(move |tx| (rx, tx))).collect(),
+            Pairs::Only(pairs) => pairs.iter().cloned().filter(|&(rx, tx)| rx < nr && tx < nc).collect(),
+        };
+        let (first, last) = self.tones.unwrap_or((0, tones.max(1) - 1));
+        let range = first.min(tones)..(last + 1).min(tones);
+
+        let row = |rx: usize, tx: usize| &s.csi[rx][tx][range.clone()];
+        let csi = match self.values {
+            View::Amplitude => Rows::Real(pairs.iter().map(|&(rx, tx)| row(rx, tx).iter().map(|c| abs(*c)).collect()).collect()),
+            View::Phase => Rows::Real(pairs.iter().map(|&(rx, tx)| row(rx, tx).iter().map(|c| phase(*c)).collect()).collect()),
+            View::Complex => Rows::Complex(pairs.iter().map(|&(rx, tx)| row(rx, tx).to_vec()).collect()),
+        };
+
+        Frame {
+            date: s.date,
+            device: s.device.clone(),
+            x: s.x,
+            y: s.y,
+            tstamp: s.status.tstamp,
+            rssi: s.status.rssi,
+            dropped,
+            first_tone: range.start,
+            pairs,
+            csi,
+        }
+    }
+}
+
+/// One row per antenna pair
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum Rows {
+    Real(Vec<Vec<f64>>),
+    Complex(Vec<Vec<ComplexDef<isize>>>),
+}
+
+/// Message sent to a subscriber for every sample
+#[derive(Clone, Debug, Serialize)]
+struct Frame {
+    date: DateTime<Utc>,
+    device: String,
+    x: f64,
+    y: f64,
+    tstamp: u64,
+    rssi: u8,
+    /// Frames dropped for this subscriber since the previous one
+    dropped: usize,
+    /// Subcarrier of the first value in each row
+    first_tone: usize,
+    /// `(rx, tx)` of each row of `csi`
+    pairs: Vec<(usize, usize)>,
+    csi: Rows,
+}
+
+struct Subscriber {
+    sub: Subscription,
+    tx: mpsc::Sender<ws::Message>,
+    /// Matching samples seen, for `every`
+    seen: usize,
+    /// Dropped since the last delivered frame
+    dropped: usize,
+}
+
+/// Fan-out of ingested samples to WebSocket subscribers.
+///
+/// Every subscriber has a bounded queue; when a client does not keep up
+/// its frames are dropped, ingestion never waits for it.
+#[derive(Default)]
+pub struct Hub {
+    subscribers: Vec<Subscriber>,
+    /// Frames dropped for slow subscribers since start
+    pub dropped: usize,
+}
+
+impl Hub {
+    fn subscribe(&mut self, sub: Subscription) -> (mpsc::Sender<ws::Message>, mpsc::Receiver<ws::Message>) {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        self.subscribers.push(Subscriber {
+            sub,
+            tx: tx.clone(),
+            seen: 0,
+            dropped: 0,
+        });
+
+        (tx, rx)
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Queue a sample for every matching subscriber, forgetting the ones
+    /// that went away
+    pub fn publish(&mut self, s: &Sample) {
+        let mut dropped = 0;
+
+        self.subscribers.retain(|sub| !sub.tx.is_closed());
+        for sub in self.subscribers.iter_mut().filter(|sub| sub.sub.matches(s)) {
+            sub.seen += 1;
+            if (sub.seen - 1) % sub.sub.every != 0 {
+                continue;
+            }
+
+            let text = match serde_json::to_string(&sub.sub.frame(s, sub.dropped)) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("Failed to encode frame: {}", e);
+                    continue;
+                }
+            };
+            match sub.tx.try_send(ws::Message::Text(text)) {
+                Ok(()) => sub.dropped = 0,
+                Err(e) if e.is_full() => {
+                    sub.dropped += 1;
+                    dropped += 1;
+                }
+                // closed, removed on the next sample
+                Err(_) => {}
+            }
+        }
+
+        self.dropped += dropped;
+    }
+}
+
+/// Complete the WebSocket handshake and stream `sub` to the client.
+///
+/// Pings are answered and a close frame ends the stream; anything else the
+/// client sends is ignored.
+pub fn start(req: &HttpRequest, mut payload: web::Payload, hub: &mut Hub, sub: Subscription) -> Result<HttpResponse, Error> {
+    let mut res = ws::handshake(req.head())?;
+    let (mut control, rx) = hub.subscribe(sub);
+
+    actix_rt::spawn(async move {
+        let mut codec = ws::Codec::new();
+        let mut buf = BytesMut::new();
+
+        while let Some(chunk) = payload.next().await {
+            match chunk {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
+                Err(_) => break,
+            }
+
+            loop {
+                let reply = match codec.decode(&mut buf) {
+                    Ok(Some(ws::Frame::Ping(msg))) => ws::Message::Pong(msg),
+                    Ok(Some(ws::Frame::Close(reason))) => ws::Message::Close(reason),
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(_) => ws::Message::Close(None),
+                };
+
+                let close = matches!(reply, ws::Message::Close(_));
+                let _ = control.try_send(reply);
+                if close {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut codec = ws::Codec::new();
+    let frames = rx
+        .scan(false, |closed, msg| {
+            if *closed {
+                return future::ready(None);
+            }
+            *closed = matches!(msg, ws::Message::Close(_));
+            future::ready(Some(msg))
+        })
+        .map(move |msg| {
+            let mut buf = BytesMut::new();
+            codec.encode(msg, &mut buf).map(|()| buf.freeze())
+        });
+
+    Ok(res.streaming(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{date, frame};
+
+    /// 2 x 2 antennas and 4 tones, `rx * 100 + tx * 10 + tone` each
+    fn sample(device: &str) -> Sample {
+        let mut s = frame(device, date("2020-06-01T10:00:00Z"), (2, 2, 4), 0);
+        s.csi = (0..2).map(|rx| (0..2).map(|tx| (0..4).map(|k| ComplexDef {
+            re: (rx * 100 + tx * 10 + k) as isize,
+            im: 0,
+        }).collect()).collect()).collect();
+        s
+    }
+
+    fn text(msg: ws::Message) -> serde_json::Value {
+        match msg {
+            ws::Message::Text(t) => serde_json::from_str(&t).unwrap(),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn filters_and_drops() {
+        let sub = Subscription::new(&Params {
+            device: Some("a".into()),
+            pairs: Some("1:0".into()),
+            tones: Some("1-2".into()),
+            every: Some(2),
+            values: View::Complex,
+        }).unwrap();
+
+        let mut hub = Hub::default();
+        let (_, mut rx) = hub.subscribe(sub);
+
+        for _ in 0..4 {
+            hub.publish(&sample("a"));
+            hub.publish(&sample("b"));
+        }
+
+        // every second sample of device a
+        let frame = text(rx.try_next().unwrap().unwrap());
+        assert_eq!(frame["device"], "a");
+        assert_eq!(frame["pairs"], serde_json::json!([[1, 0]]));
+        assert_eq!(frame["first_tone"], 1);
+        assert_eq!(frame["csi"][0][0]["re"], 101);
+        assert_eq!(frame["csi"][0][1]["re"], 102);
+        text(rx.try_next().unwrap().unwrap());
+        assert!(rx.try_next().is_err());
+
+        // a client that never reads loses frames instead of blocking
+        for _ in 0..(QUEUE + 10) * 2 {
+            hub.publish(&sample("a"));
+        }
+        assert!(hub.dropped > 0);
+
+        drop(rx);
+        hub.publish(&sample("a"));
+        assert!(hub.subscribers.is_empty());
+    }
+
+    #[test]
+    fn tone_ranges() {
+        assert_eq!(parse_tones("0-29"), Ok((0, 29)));
+        assert_eq!(parse_tones("5"), Ok((5, 5)));
+        assert!(parse_tones("9-3").is_err());
+        assert!(parse_tones("a-b").is_err());
+    }
+}
@@ -0,0 +1,464 @@
+//! Guided site survey over a grid of reference points, `--survey`
+//!
+//! The operator walks the points in order. At each one they confirm being in
+//! place (`/survey/next` or Enter at the prompt), samples are collected with
+//! the point's position until the target is reached, and the survey waits
+//! for the operator at the next point. Samples received while walking
+//! between points get the unknown position `(-1, -1)`. A point held by a
+//! device that went quiet can be finished early by the operator.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::prelude::*;
+use csi_types::stats::FrameStats;
+use serde::Serialize;
+
+use crate::types::Sample;
+
+/// When a point is complete, and when it is flagged for another visit
+#[derive(Clone, Debug, Serialize)]
+pub struct Target {
+    /// Samples from every device heard so far in the survey
+    pub samples: Option<usize>,
+    /// Seconds of collection
+    pub secs: Option<u64>,
+    /// Points with fewer samples from a device are flagged
+    pub min_samples: usize,
+    /// Frames with a lower RSSI are low quality
+    pub min_rssi: Option<u8>,
+    /// Points where a device's share of low-quality and lost frames is
+    /// higher are flagged
+    pub max_bad: f64,
+}
+
+/// Frames of one device at a point
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceProgress {
+    pub samples: usize,
+    /// Frames with a PHY error or an RSSI below `Target::min_rssi`
+    pub low_quality: usize,
+    /// Frames missing according to the sequence numbers
+    pub lost: u64,
+    #[serde(skip)]
+    timing: FrameStats,
+}
+
+impl DeviceProgress {
+    fn new() -> Self {
+        Self {
+            samples: 0,
+            low_quality: 0,
+            lost: 0,
+            // only the sequence numbers are of interest
+            timing: FrameStats::new(1, 0),
+        }
+    }
+
+    /// Share of low-quality and lost frames
+    pub fn bad(&self) -> f64 {
+        let expected = self.samples as u64 + self.lost;
+        if expected > 0 {
+            (self.low_quality as u64 + self.lost) as f64 / expected as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub devices: BTreeMap<String, DeviceProgress>,
+    /// Why the point should be collected again, empty if it is fine
+    pub flags: Vec<String>,
+}
+
+impl Point {
+    fn new(x: f64, y: f64) -> Self {
+        Self {
+            x,
+            y,
+            started: None,
+            finished: None,
+            devices: BTreeMap::new(),
+            flags: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Waiting for the operator to confirm being at the current point
+    Waiting,
+    Collecting,
+    /// Every point was collected
+    Complete,
+}
+
+/// Progress without the per-point details, for `/status`
+#[derive(Clone, Debug, Serialize)]
+pub struct Summary {
+    pub state: State,
+    pub current: usize,
+    pub points: usize,
+    pub done: usize,
+    /// Indices of the flagged points
+    pub flagged: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Survey {
+    pub state: State,
+    /// Index of the point being collected or walked to
+    pub current: usize,
+    pub target: Target,
+    pub points: Vec<Point>,
+    /// Devices heard at any point; a point missing one of them is flagged
+    #[serde(skip)]
+    known: BTreeSet<String>,
+}
+
+/// Reference points, one `x,y` per line.
+///
+/// Fields may also be separated by whitespace. Blank lines, `#` comments
+/// and a header line such as `x,y` are skipped.
+pub fn parse_points(s: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut points = Vec::new();
+    let mut header = true;
+
+    for (n, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        let point = match fields.as_slice() {
+            [x, y] => x.parse().and_then(|x| y.parse().map(|y| (x, y))).ok(),
+            _ => None,
+        };
+
+        match point {
+            Some(p) => points.push(p),
+            None if header => (),
+            None => return Err(format!("line {}: expected x,y, got `{}`", n + 1, line)),
+        }
+        header = false;
+    }
+
+    if points.is_empty() {
+        return Err("no points".to_string());
+    }
+    Ok(points)
+}
+
+pub fn load_points<P: AsRef<Path>>(path: P) -> io::Result<Vec<(f64, f64)>> {
+    let path = path.as_ref();
+    parse_points(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+impl Survey {
+    pub fn new(points: Vec<(f64, f64)>, target: Target) -> Self {
+        Self {
+            state: State::Waiting,
+            current: 0,
+            target,
+            points: points.into_iter().map(|(x, y)| Point::new(x, y)).collect(),
+            known: BTreeSet::new(),
+        }
+    }
+
+    /// Position of a sample received now, `None` while nothing is collected
+    pub fn position(&self) -> Option<(f64, f64)> {
+        match self.state {
+            State::Collecting => {
+                let p = &self.points[self.current];
+                Some((p.x, p.y))
+            }
+            _ => None,
+        }
+    }
+
+    /// The operator is at the current point, or at point `index` to collect
+    /// it again; start collecting
+    pub fn confirm(&mut self, index: Option<usize>, now: DateTime<Utc>) -> Result<usize, String> {
+        if self.state == State::Collecting {
+            return Err(format!("point {} is still collecting", self.current));
+        }
+
+        let index = match index {
+            Some(i) => i,
+            None if self.state == State::Complete => return Err("the survey is complete".to_string()),
+            None => self.current,
+        };
+        let len = self.points.len();
+        let point = self.points.get_mut(index)
+            .ok_or_else(|| format!("no point {}, the survey has {}", index, len))?;
+
+        *point = Point::new(point.x, point.y);
+        point.started = Some(now);
+        self.current = index;
+        self.state = State::Collecting;
+
+        Ok(index)
+    }
+
+    /// Account a sample; returns the index of the point it completed
+    pub fn record(&mut self, sample: &Sample) -> Option<usize> {
+        if self.state != State::Collecting {
+            return None;
+        }
+
+        let min_rssi = self.target.min_rssi;
+        let d = self.points[self.current].devices.entry(sample.device.clone())
+            .or_insert_with(DeviceProgress::new);
+        d.samples += 1;
+        if sample.status.phyerr != 0 || min_rssi.map_or(false, |min| sample.status.rssi < min) {
+            d.low_quality += 1;
+        }
+        d.timing.push(sample.status.tstamp, &sample.payload);
+        d.lost = d.timing.lost;
+        self.known.insert(sample.device.clone());
+
+        self.check(sample.date)
+    }
+
+    /// Complete the current point if its time is up; returns its index
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Option<usize> {
+        if self.state != State::Collecting {
+            return None;
+        }
+
+        self.check(now)
+    }
+
+    /// The operator gave up waiting for the target; complete the current
+    /// point with what it has and flag it
+    pub fn finish(&mut self, now: DateTime<Utc>) -> Result<usize, String> {
+        if self.state != State::Collecting {
+            return Err("no point is collecting".to_string());
+        }
+
+        Ok(self.complete(now, true))
+    }
+
+    fn check(&mut self, now: DateTime<Utc>) -> Option<usize> {
+        let point = &self.points[self.current];
+        // a device that went quiet holds the point until `secs` runs out or
+        // the operator finishes it, it is not left out of the count
+        let enough = self.target.samples.map_or(false, |n| {
+            let samples = |id: &String| point.devices.get(id).map_or(0, |d| d.samples);
+            !point.devices.is_empty() && self.known.iter().all(|id| samples(id) >= n)
+        });
+        let timeout = match (self.target.secs, point.started) {
+            (Some(secs), Some(started)) => now - started >= chrono::Duration::seconds(secs as i64),
+            _ => false,
+        };
+        if !(enough || timeout) {
+            return None;
+        }
+
+        Some(self.complete(now, false))
+    }
+
+    fn complete(&mut self, now: DateTime<Utc>, early: bool) -> usize {
+        let index = self.current;
+        let mut flags = self.flags(&self.points[index]);
+        if early {
+            flags.insert(0, "finished early".to_string());
+        }
+        let point = &mut self.points[index];
+        point.finished = Some(now);
+        point.flags = flags;
+
+        match self.points.iter().position(|p| p.finished.is_none()) {
+            Some(next) => {
+                self.current = next;
+                self.state = State::Waiting;
+            }
+            None => self.state = State::Complete,
+        }
+
+        index
+    }
+
+    fn flags(&self, point: &Point) -> Vec<String> {
+        if point.devices.is_empty() {
+            return vec!["no samples".to_string()];
+        }
+
+        let mut flags = Vec::new();
+        for id in self.known.iter().filter(|id| !point.devices.contains_key(*id)) {
+            flags.push(format!("{}: no samples", id));
+        }
+        for (id, d) in &point.devices {
+            if d.samples < self.target.min_samples {
+                flags.push(format!("{}: {} samples, at least {} expected", id, d.samples, self.target.min_samples));
+            }
+            if d.bad() > self.target.max_bad {
+                flags.push(format!("{}: {:.0}% low-quality or lost frames", id, d.bad() * 100.0));
+            }
+        }
+        flags
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            state: self.state,
+            current: self.current,
+            points: self.points.len(),
+            done: self.points.iter().filter(|p| p.finished.is_some()).count(),
+            flagged: self.points.iter()
+                .enumerate()
+                .filter(|(_, p)| !p.flags.is_empty())
+                .map(|(i, _)| i)
+                .collect(),
+        }
+    }
+
+    /// What the operator should do next
+    pub fn prompt(&self) -> String {
+        match self.state {
+            State::Waiting => {
+                let p = &self.points[self.current];
+                format!("Go to point {} at ({}, {}) and press Enter", self.current, p.x, p.y)
+            }
+            State::Collecting => format!("Collecting point {}... (f to finish it now)", self.current),
+            State::Complete => {
+                let flagged = self.summary().flagged;
+                if flagged.is_empty() {
+                    "Survey complete".to_string()
+                } else {
+                    format!("Survey complete, flagged points: {:?}; enter a point's number to collect it again", flagged)
+                }
+            }
+        }
+    }
+
+    /// Print the outcome of point `index` and what comes next
+    pub fn announce(&self, index: usize) {
+        let p = &self.points[index];
+        let samples: usize = p.devices.values().map(|d| d.samples).sum();
+        let summary = self.summary();
+
+        println!("Point {} at ({}, {}) done: {} samples [{}/{}]", index, p.x, p.y, samples, summary.done, summary.points);
+        for flag in &p.flags {
+            println!("  flagged: {}", flag);
+        }
+        println!("{}", self.prompt());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn target() -> Target {
+        Target {
+            samples: Some(3),
+            secs: None,
+            min_samples: 3,
+            min_rssi: Some(20),
+            max_bad: 0.1,
+        }
+    }
+
+    fn sample(device: &str, date: &str, rssi: u8) -> Sample {
+        let mut s = testutil::sample(device, testutil::date(date), 0);
+        s.status.rssi = rssi;
+        s
+    }
+
+    #[test]
+    fn points() {
+        assert_eq!(parse_points("x,y\n0,0\n# row 2\n\n1.5 2\n").unwrap(), vec![(0.0, 0.0), (1.5, 2.0)]);
+        assert!(parse_points("0,0\n1\n").unwrap_err().starts_with("line 2"));
+        assert!(parse_points("x,y\n").is_err());
+    }
+
+    #[test]
+    fn walk() {
+        let mut s = Survey::new(vec![(0.0, 0.0), (1.0, 0.0)], Target { secs: Some(60), ..target() });
+        assert_eq!(s.position(), None);
+        assert_eq!(s.record(&sample("a", "2020-06-01T10:00:00Z", 40)), None);
+        assert!(s.points[0].devices.is_empty());
+
+        assert_eq!(s.confirm(None, "2020-06-01T10:00:00Z".parse().unwrap()), Ok(0));
+        assert!(s.confirm(None, "2020-06-01T10:00:00Z".parse().unwrap()).is_err());
+        assert_eq!(s.position(), Some((0.0, 0.0)));
+        assert_eq!(s.record(&sample("a", "2020-06-01T10:00:01Z", 40)), None);
+        assert_eq!(s.record(&sample("b", "2020-06-01T10:00:01Z", 40)), None);
+        assert_eq!(s.record(&sample("a", "2020-06-01T10:00:02Z", 40)), None);
+        assert_eq!(s.record(&sample("a", "2020-06-01T10:00:02Z", 40)), None);
+        assert_eq!(s.record(&sample("b", "2020-06-01T10:00:02Z", 40)), None);
+        assert_eq!(s.record(&sample("b", "2020-06-01T10:00:03Z", 40)), Some(0));
+
+        assert_eq!(s.state, State::Waiting);
+        assert_eq!(s.current, 1);
+        assert!(s.points[0].flags.is_empty());
+        assert_eq!(s.position(), None);
+
+        // b is gone and a is weak, the point waits for b until the time is up
+        s.confirm(None, "2020-06-01T10:01:00Z".parse().unwrap()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(s.record(&sample("a", "2020-06-01T10:01:01Z", 10)), None);
+        }
+        assert_eq!(s.tick("2020-06-01T10:02:00Z".parse().unwrap()), Some(1));
+        assert_eq!(s.state, State::Complete);
+        assert_eq!(s.points[1].flags, vec!["b: no samples", "a: 100% low-quality or lost frames"]);
+        assert_eq!(s.summary().flagged, vec![1]);
+        assert!(s.confirm(None, "2020-06-01T10:02:00Z".parse().unwrap()).is_err());
+
+        // collect it again
+        assert_eq!(s.confirm(Some(1), "2020-06-01T10:02:00Z".parse().unwrap()), Ok(1));
+        assert!(s.points[1].devices.is_empty());
+        assert!(s.confirm(Some(5), "2020-06-01T10:02:00Z".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut s = Survey::new(vec![(0.0, 0.0)], Target { samples: None, secs: Some(10), ..target() });
+        s.confirm(None, "2020-06-01T10:00:00Z".parse().unwrap()).unwrap();
+        assert_eq!(s.record(&sample("a", "2020-06-01T10:00:01Z", 40)), None);
+        assert_eq!(s.tick("2020-06-01T10:00:09Z".parse().unwrap()), None);
+        assert_eq!(s.tick("2020-06-01T10:00:10Z".parse().unwrap()), Some(0));
+
+        assert_eq!(s.points[0].flags, vec!["a: 1 samples, at least 3 expected"]);
+        assert_eq!(s.tick("2020-06-01T10:00:11Z".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn finish_early() {
+        let mut s = Survey::new(vec![(0.0, 0.0), (1.0, 0.0)], target());
+        assert!(s.finish("2020-06-01T10:00:00Z".parse().unwrap()).is_err());
+
+        s.confirm(None, "2020-06-01T10:00:00Z".parse().unwrap()).unwrap();
+        for device in &["a", "b", "a", "b", "a"] {
+            s.record(&sample(device, "2020-06-01T10:00:01Z", 40));
+        }
+        assert_eq!(s.record(&sample("b", "2020-06-01T10:00:01Z", 40)), Some(0));
+
+        // b went quiet and there is no time limit, only the operator ends it
+        s.confirm(None, "2020-06-01T10:01:00Z".parse().unwrap()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(s.record(&sample("a", "2020-06-01T10:01:01Z", 40)), None);
+        }
+        assert_eq!(s.tick("2020-06-01T11:00:00Z".parse().unwrap()), None);
+        assert_eq!(s.finish("2020-06-01T11:00:00Z".parse().unwrap()), Ok(1));
+
+        assert_eq!(s.state, State::Complete);
+        assert_eq!(s.points[1].flags, vec!["finished early", "b: no samples"]);
+        assert_eq!(s.summary().flagged, vec![1]);
+    }
+}
@@ -0,0 +1,49 @@
+//! Factories shared by the unit tests
+
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use csi_types::ser::ComplexDef;
+use csi_types::CSIStruct;
+
+use crate::types::Sample;
+
+/// An empty directory for one test, `name` must be unique in the crate
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("recv_csi_server_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn date(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+/// A frame of `nr` x `nc` antennas and `tones` tones, all `re - re i`.
+/// `re` is also the frame's hardware timestamp and `x`.
+pub fn frame(device: &str, date: DateTime<Utc>, (nr, nc, tones): (usize, usize, usize), re: isize) -> Sample {
+    let mut status = CSIStruct::new();
+    status.nr = nr as u8;
+    status.nc = nc as u8;
+    status.num_tones = tones as u8;
+    status.tstamp = re as u64;
+    status.rssi = 40;
+
+    Sample {
+        date,
+        device: device.to_string(),
+        x: re as f64,
+        y: -2.5,
+        csi: vec![vec![vec![ComplexDef { re, im: -re }; tones]; nc]; nr],
+        status,
+        payload: vec![1, 2, 3],
+        session: None,
+    }
+}
+
+/// `frame` with 2 x 1 antennas and 3 tones
+pub fn sample(device: &str, date: DateTime<Utc>, re: isize) -> Sample {
+    frame(device, date, (2, 1, 3), re)
+}
@@ -0,0 +1,43 @@
+//! HTTPS with rustls
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, PrivateKey, ServerConfig};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// First PKCS#8 or RSA private key of a PEM file
+fn private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pkcs8 = pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid(format!("{}: invalid PEM", path.display())))?;
+    let keys = if pkcs8.is_empty() {
+        rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| invalid(format!("{}: invalid PEM", path.display())))?
+    } else {
+        pkcs8
+    };
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(format!("{}: no private key found", path.display())))
+}
+
+/// Server configuration from a PEM certificate chain and its private key
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let chain = certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| invalid(format!("{}: invalid PEM", cert.display())))?;
+    if chain.is_empty() {
+        return Err(invalid(format!("{}: no certificate found", cert.display())));
+    }
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, private_key(key)?)
+        .map_err(|e| invalid(format!("{}: {}", key.display(), e)))?;
+
+    Ok(config)
+}
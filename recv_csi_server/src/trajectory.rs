@@ -0,0 +1,375 @@
+//! Ground-truth trajectories imported into a session
+//!
+//! A motion-capture or UWB system records where the subject was, with its
+//! own clock. Once imported into a session, the position of each of the
+//! session's samples is interpolated from the trajectory at the sample's
+//! capture time, and exports carry it as the sample's pose.
+
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+
+/// Largest trajectory file accepted by `/sessions/trajectory`
+pub const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// Trajectory file formats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// With a header row naming the columns
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            _ => Err(format!("unknown trajectory format '{}', expected csv or jsonl", s)),
+        }
+    }
+}
+
+impl Format {
+    /// JSON lines start with an object, anything else is taken as CSV
+    pub fn detect(text: &str) -> Self {
+        if text.trim_start().starts_with('{') {
+            Format::JsonLines
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// On the server's clock, the offset applied
+    pub t: DateTime<Utc>,
+    pub x: f64,
+    pub y: f64,
+    pub z: Option<f64>,
+    /// Degrees
+    pub heading: Option<f64>,
+}
+
+/// Position of a sample: x, y, z and heading
+pub type Pose = [Option<f64>; 4];
+
+/// Why a sample has no position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outside {
+    Before,
+    After,
+    /// Between two points further apart than `max_gap_ms`
+    Gap,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trajectory {
+    /// Added to the trajectory's timestamps to get the server's time
+    pub offset_ms: i64,
+    /// Samples between two points further apart are not interpolated
+    pub max_gap_ms: Option<i64>,
+    /// By time
+    pub points: Vec<TrackPoint>,
+}
+
+/// How a trajectory covers the samples of its session
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub points: usize,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub offset_ms: i64,
+    pub max_gap_ms: Option<i64>,
+    pub samples: usize,
+    pub interpolated: usize,
+    /// Samples captured before the first point
+    pub before: usize,
+    /// Samples captured after the last point
+    pub after: usize,
+    /// Samples in a gap longer than `max_gap_ms`
+    pub in_gaps: usize,
+}
+
+/// A timestamp: RFC 3339, or seconds since the Unix epoch
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(secs) = value.parse::<f64>() {
+        let micros = (secs * 1e6).round() as i64;
+        return Utc.timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32)
+            .single()
+            .ok_or_else(|| format!("timestamp out of range: {}", value));
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|d| d.with_timezone(&Utc))
+        .map_err(|e| format!("invalid timestamp '{}': {}", value, e))
+}
+
+/// Names accepted for each column, lowercase
+const TIME_NAMES: [&str; 4] = ["t", "time", "timestamp", "date"];
+const HEADING_NAMES: [&str; 2] = ["heading", "yaw"];
+
+fn parse_csv(text: &str) -> Result<Vec<TrackPoint>, String> {
+    let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(text.as_bytes());
+    let header: Vec<String> = rdr.headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(str::to_lowercase)
+        .collect();
+    let find = |names: &[&str]| header.iter().position(|h| names.contains(&h.as_str()));
+    let (t, x, y) = match (find(&TIME_NAMES), find(&["x"]), find(&["y"])) {
+        (Some(t), Some(x), Some(y)) => (t, x, y),
+        _ => return Err("the header needs a time (t, time, timestamp or date), x and y column".to_string()),
+    };
+    let (z, heading) = (find(&["z"]), find(&HEADING_NAMES));
+
+    let mut points = vec![];
+    for (n, record) in rdr.records().enumerate() {
+        let record = record.map_err(|e| e.to_string())?;
+        let line = n + 2;
+        let field = |i: usize| record.get(i).unwrap_or("");
+        let number = |i: usize| field(i).parse::<f64>()
+            .map_err(|_| format!("line {}: invalid number '{}'", line, field(i)));
+        let optional = |i: Option<usize>| match i {
+            Some(i) if !field(i).is_empty() => number(i).map(Some),
+            _ => Ok(None),
+        };
+
+        points.push(TrackPoint {
+            t: parse_time(field(t)).map_err(|e| format!("line {}: {}", line, e))?,
+            x: number(x)?,
+            y: number(y)?,
+            z: optional(z)?,
+            heading: optional(heading)?,
+        });
+    }
+
+    Ok(points)
+}
+
+fn parse_json_lines(text: &str) -> Result<Vec<TrackPoint>, String> {
+    let mut points = vec![];
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let err = |msg: String| format!("line {}: {}", n + 1, msg);
+
+        let obj: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)
+            .map_err(|e| err(e.to_string()))?;
+        let get = |names: &[&str]| names.iter().find_map(|name| obj.get(*name));
+        let number = |names: &[&str]| match get(names) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(v) => v.as_f64().map(Some).ok_or_else(|| err(format!("{} is not a number", names[0]))),
+        };
+
+        let t = match get(&TIME_NAMES) {
+            Some(serde_json::Value::String(s)) => parse_time(s),
+            Some(serde_json::Value::Number(n)) => parse_time(&n.to_string()),
+            _ => Err("no time (t, time, timestamp or date)".to_string()),
+        }.map_err(err)?;
+
+        points.push(TrackPoint {
+            t,
+            x: number(&["x"])?.ok_or_else(|| err("no x".to_string()))?,
+            y: number(&["y"])?.ok_or_else(|| err("no y".to_string()))?,
+            z: number(&["z"])?,
+            heading: number(&HEADING_NAMES)?,
+        });
+    }
+
+    Ok(points)
+}
+
+/// Shortest turn from `a` to `b`, in degrees
+fn turn(a: f64, b: f64) -> f64 {
+    (b - a + 540.0).rem_euclid(360.0) - 180.0
+}
+
+impl Trajectory {
+    /// Parse a trajectory file, shifting its timestamps by `offset_ms`
+    pub fn parse(text: &str, format: Format, offset_ms: i64, max_gap_ms: Option<i64>) -> Result<Self, String> {
+        let mut points = match format {
+            Format::Csv => parse_csv(text)?,
+            Format::JsonLines => parse_json_lines(text)?,
+        };
+        if points.len() < 2 {
+            return Err("a trajectory needs at least two points".to_string());
+        }
+        if points.iter().any(|p| {
+            ![p.x, p.y].iter().chain(p.z.iter()).chain(p.heading.iter()).all(|v| v.is_finite())
+        }) {
+            return Err("positions and headings must be finite".to_string());
+        }
+
+        if let Some(gap) = max_gap_ms {
+            if gap < 0 || gap.checked_mul(1000).is_none() {
+                return Err(format!("max_gap_ms must be between 0 and {}", i64::MAX / 1000));
+            }
+        }
+
+        // no date is that far off anyway, the clamp only keeps `milliseconds`
+        // from panicking
+        let offset = chrono::Duration::milliseconds(offset_ms.max(-i64::MAX));
+        for p in &mut points {
+            p.t = p.t.checked_add_signed(offset)
+                .ok_or_else(|| format!("offset_ms {} moves {} out of range", offset_ms, p.t))?;
+        }
+        points.sort_by_key(|p| p.t);
+
+        Ok(Self {
+            offset_ms,
+            max_gap_ms,
+            points,
+        })
+    }
+
+    /// Interpolated pose at `date`
+    pub fn at(&self, date: DateTime<Utc>) -> Result<Pose, Outside> {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(Outside::Before),
+        };
+        if date < first.t {
+            return Err(Outside::Before);
+        }
+        if date > last.t {
+            return Err(Outside::After);
+        }
+
+        // on a point, whatever the gap to the next one
+        let i = self.points.partition_point(|p| p.t <= date);
+        let (a, b) = (&self.points[i - 1], self.points.get(i));
+        let b = match b {
+            Some(b) if a.t < date => b,
+            _ => return Ok([Some(a.x), Some(a.y), a.z, a.heading]),
+        };
+        let span = (b.t - a.t).num_microseconds().unwrap_or(i64::MAX);
+        if self.max_gap_ms.map_or(false, |gap| span > gap.saturating_mul(1000)) {
+            return Err(Outside::Gap);
+        }
+
+        let f = if span > 0 {
+            (date - a.t).num_microseconds().unwrap_or(0) as f64 / span as f64
+        } else {
+            0.0
+        };
+        let lerp = |u: f64, v: f64| u + (v - u) * f;
+        let both = |u: Option<f64>, v: Option<f64>| match (u, v) {
+            (Some(u), Some(v)) => Some((u, v)),
+            _ => None,
+        };
+
+        Ok([
+            Some(lerp(a.x, b.x)),
+            Some(lerp(a.y, b.y)),
+            both(a.z, b.z).map(|(u, v)| lerp(u, v)),
+            both(a.heading, b.heading).map(|(u, v)| (u + turn(u, v) * f).rem_euclid(360.0)),
+        ])
+    }
+
+    /// Coverage of the samples captured at `dates`
+    pub fn report(&self, dates: &[DateTime<Utc>]) -> Report {
+        let mut report = Report {
+            points: self.points.len(),
+            first: self.points[0].t,
+            last: self.points[self.points.len() - 1].t,
+            offset_ms: self.offset_ms,
+            max_gap_ms: self.max_gap_ms,
+            samples: dates.len(),
+            interpolated: 0,
+            before: 0,
+            after: 0,
+            in_gaps: 0,
+        };
+
+        for date in dates {
+            match self.at(*date) {
+                Ok(_) => report.interpolated += 1,
+                Err(Outside::Before) => report.before += 1,
+                Err(Outside::After) => report.after += 1,
+                Err(Outside::Gap) => report.in_gaps += 1,
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        let t = Trajectory::parse(
+            "Time, X, Y, Z, yaw\n1591005601.5, 1, 0, , 350\n2020-06-01T10:00:00Z, 0, 0, 1, 10\n",
+            Format::Csv, 500, None,
+        ).unwrap();
+
+        assert_eq!(t.points.len(), 2);
+        assert_eq!(t.points[0].t, date("2020-06-01T10:00:00.500Z"));
+        assert_eq!(t.points[0].z, Some(1.0));
+        assert_eq!(t.points[1].t, date("2020-06-01T10:00:02Z"));
+        assert_eq!(t.points[1].z, None);
+
+        assert!(Trajectory::parse("t,x\n1,2\n3,4\n", Format::Csv, 0, None).unwrap_err().contains("header"));
+        assert!(Trajectory::parse("t,x,y\n1,2,a\n3,4,5\n", Format::Csv, 0, None).unwrap_err().starts_with("line 2"));
+        assert!(Trajectory::parse("t,x,y\n1,2,3\n", Format::Csv, 0, None).is_err());
+
+        let text = "t,x,y\n1,0,0\n2,1,1\n";
+        assert!(Trajectory::parse(text, Format::Csv, i64::MAX, None).unwrap_err().contains("out of range"));
+        assert!(Trajectory::parse(text, Format::Csv, i64::MIN, None).unwrap_err().contains("out of range"));
+        assert!(Trajectory::parse(text, Format::Csv, 0, Some(i64::MAX)).unwrap_err().starts_with("max_gap_ms"));
+        assert!(Trajectory::parse(text, Format::Csv, 0, Some(-1)).unwrap_err().starts_with("max_gap_ms"));
+    }
+
+    #[test]
+    fn json_lines() {
+        let text = "{\"t\": \"2020-06-01T10:00:00Z\", \"x\": 0, \"y\": 0}\n\n{\"timestamp\": 1591005610, \"x\": 10, \"y\": 5, \"heading\": 90}\n";
+        assert_eq!(Format::detect(text), Format::JsonLines);
+
+        let t = Trajectory::parse(text, Format::JsonLines, -1000, None).unwrap();
+        assert_eq!(t.points[1].t, date("2020-06-01T10:00:09Z"));
+        assert_eq!(t.points[1].heading, Some(90.0));
+
+        assert!(Trajectory::parse("{\"t\": 1, \"x\": 0}\n{\"t\": 2, \"x\": 0, \"y\": 0}\n", Format::JsonLines, 0, None)
+            .unwrap_err().starts_with("line 1: no y"));
+    }
+
+    #[test]
+    fn interpolate() {
+        let point = |t: &str, x: f64, heading: f64| TrackPoint {
+            t: date(t),
+            x,
+            y: 2.0 * x,
+            z: Some(1.0),
+            heading: Some(heading),
+        };
+        let t = Trajectory {
+            offset_ms: 0,
+            max_gap_ms: Some(5000),
+            points: vec![
+                point("2020-06-01T10:00:00Z", 0.0, 350.0),
+                point("2020-06-01T10:00:04Z", 4.0, 30.0),
+                point("2020-06-01T10:00:20Z", 20.0, 30.0),
+            ],
+        };
+
+        assert_eq!(t.at(date("2020-06-01T10:00:01Z")), Ok([Some(1.0), Some(2.0), Some(1.0), Some(0.0)]));
+        assert_eq!(t.at(date("2020-06-01T10:00:04Z")).unwrap()[0], Some(4.0));
+        assert_eq!(t.at(date("2020-06-01T10:00:20Z")).unwrap()[0], Some(20.0));
+        assert_eq!(t.at(date("2020-06-01T09:59:59Z")), Err(Outside::Before));
+        assert_eq!(t.at(date("2020-06-01T10:00:21Z")), Err(Outside::After));
+        assert_eq!(t.at(date("2020-06-01T10:00:10Z")), Err(Outside::Gap));
+    }
+}
@@ -1,32 +1,410 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use chrono::prelude::*;
+use csi_types::CSIStruct;
+use csi_types::stats::FrameStats;
+use csi_types::ser::{abs, phase, ComplexDef};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+use crate::export::csv::CsvConfig;
+#[cfg(feature = "columnar")]
+use crate::export::parquet::Dataset;
+use crate::metrics::Signal;
+use crate::output::{Output, Rotation};
+use crate::storage::Storage;
+use crate::store::Filter;
+use crate::stream::Hub;
+use crate::survey::Survey;
+
+/// Where the positions of a session's samples come from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Receive {
+    /// The latest position posted to `/post_xy`
     Realtime,
+    /// A fixed position for the whole session
     Predefined(f64, f64)
 }
 
+/// A named recording; samples received while it runs carry its name
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub started: DateTime<Utc>,
+    /// Whether someone is present in the monitored area
+    pub present: bool,
+    pub position: Receive,
+}
+
+/// Ground truth over a time span: pose, activity, subject and free-form
+/// tags. Exports join it onto the samples it covers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Assigned by the storage
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default = "Utc::now")]
+    pub start: DateTime<Utc>,
+    /// Open-ended until set
+    #[serde(default)]
+    pub end: Option<DateTime<Utc>>,
+    /// Only samples of this device, of every device if `None`
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Only samples of this session
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub x: Option<f64>,
+    #[serde(default)]
+    pub y: Option<f64>,
+    #[serde(default)]
+    pub z: Option<f64>,
+    /// Degrees
+    #[serde(default)]
+    pub heading: Option<f64>,
+    /// Category such as `walking`, `sitting` or `empty`
+    #[serde(default)]
+    pub activity: Option<String>,
+    /// Who is being recorded
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Annotation {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.end.map_or(false, |end| end < self.start) {
+            return Err("end is before start".to_string());
+        }
+        if self.x.is_some() != self.y.is_some() || (self.z.is_some() && self.x.is_none()) {
+            return Err("x and y go together, z needs both".to_string());
+        }
+        if [self.x, self.y, self.z, self.heading].iter().flatten().any(|v| !v.is_finite()) {
+            return Err("x, y, z and heading must be finite".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Whether a sample falls in the span and matches the device and session
+    pub fn covers(&self, s: &Sample) -> bool {
+        s.date >= self.start
+            && self.end.map_or(true, |end| s.date < end)
+            && self.device.as_ref().map_or(true, |d| *d == s.device)
+            && self.session.as_ref().map_or(true, |name| s.session.as_ref() == Some(name))
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WriteConfig {
-    pub write_at_least: usize,
-    pub data: Receive,
+    pub out_dir: PathBuf,
+    pub rotation: Rotation,
+    /// How often buffered rows are flushed to disk
+    pub flush_every: Duration,
+    /// How often written data is synced to disk, to bound loss on a crash
+    pub checkpoint_every: Option<Duration>,
+    pub csv: CsvConfig,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CSIData {
-    pub inner: Vec< // vector of samples
-            Vec<Vec<Vec<f64>>>
-            >,
     pub c: Option<WriteConfig>,
+    pub output: Option<Output>,
+    /// Parquet dataset partitioned by device and hour
+    #[cfg(feature = "columnar")]
+    pub dataset: Option<Dataset>,
     pub recent_xy: (f64, f64),
-    pub samples: Vec<Sample>,
+    /// The running session, if any
+    pub session: Option<Session>,
+    /// The site survey of `--survey`
+    pub survey: Option<Survey>,
+    pub storage: Box<dyn Storage>,
+    pub devices: BTreeMap<String, DeviceStats>,
+    /// RSSI and noise averages per device, for `/metrics`
+    pub signal: BTreeMap<String, Signal>,
+    /// The latest sample of every device this run, for `/events` without
+    /// reading the storage on every tick
+    pub latest: BTreeMap<String, Sample>,
+    /// Hardware timestamp intervals and sequence gaps per device, for `/stats`
+    pub timing: BTreeMap<String, FrameStats>,
+    /// WebSocket subscribers of `/ws`
+    pub hub: Hub,
+    pub started: DateTime<Utc>,
+    /// Cleared once shutdown starts, new frames are rejected afterwards
+    pub accepting: bool,
+}
+
+/// Samples read at a time by `CSIData::save_memory`
+const SAVE_CHUNK: usize = 1000;
+
+impl CSIData {
+    /// Write an accepted sample to the outputs and the storage, and pass
+    /// it on to live subscribers
+    pub fn push(&mut self, sample: Sample) -> io::Result<()> {
+        self.hub.publish(&sample);
+        self.latest.insert(sample.device.clone(), sample.clone());
+
+        if let Some(output) = self.output.as_mut() {
+            output.write(&sample)?;
+        }
+
+        #[cfg(feature = "columnar")]
+        {
+            if let Some(dataset) = self.dataset.as_mut() {
+                dataset.write(&sample)?;
+            }
+        }
+
+        self.storage.append(sample)
+    }
+
+    /// Position of a sample received now.
+    ///
+    /// During a survey it is the point being collected, or unknown while
+    /// the operator walks to the next one.
+    pub fn position(&self) -> (f64, f64) {
+        if let Some(survey) = &self.survey {
+            return survey.position().unwrap_or((-1.0, -1.0));
+        }
+
+        match self.session.as_ref().map(|s| &s.position) {
+            Some(Receive::Predefined(x, y)) => (*x, *y),
+            _ => self.recent_xy,
+        }
+    }
+
+    /// Start a session, stopping the running one. Fails with
+    /// `AlreadyExists` if the name was used before.
+    pub fn start_session(&mut self, session: Session) -> io::Result<()> {
+        self.storage.start_session(&session)?;
+        if let Some(running) = self.session.take() {
+            self.storage.stop_session(&running.name, session.started)?;
+        }
+        self.session = Some(session);
+
+        Ok(())
+    }
+
+    /// Stop the running session and return it
+    pub fn stop_session(&mut self, now: DateTime<Utc>) -> io::Result<Option<Session>> {
+        if let Some(running) = &self.session {
+            self.storage.stop_session(&running.name, now)?;
+        }
+
+        Ok(self.session.take())
+    }
+
+    /// Write buffered output
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(output) = self.output.as_mut() {
+            output.rotate(Utc::now())?;
+            output.flush()?;
+        }
+
+        self.storage.flush()
+    }
+
+    /// Sync everything written so far to disk
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        if let Some(output) = self.output.as_mut() {
+            output.rotate(Utc::now())?;
+            output.sync()?;
+        }
+
+        #[cfg(feature = "columnar")]
+        {
+            if let Some(dataset) = self.dataset.as_mut() {
+                dataset.flush()?;
+            }
+        }
+
+        self.storage.sync()
+    }
+
+    /// Persist all unsaved samples and close the output files
+    pub fn close(&mut self) -> io::Result<()> {
+        if let Some(output) = self.output.as_mut() {
+            output.close()?;
+        } else {
+            self.save_memory()?;
+        }
+
+        #[cfg(feature = "columnar")]
+        {
+            if let Some(dataset) = self.dataset.as_mut() {
+                dataset.close()?;
+            }
+        }
+
+        self.storage.close()
+    }
+
+    /// Write the samples that exist only in memory to CSV files in the
+    /// output directory, for a server that did not write them as they
+    /// arrived
+    fn save_memory(&mut self) -> io::Result<()> {
+        #[cfg(feature = "columnar")]
+        {
+            if self.dataset.is_some() {
+                return Ok(());
+            }
+        }
+        let cfg = match &self.c {
+            Some(cfg) if !self.storage.persistent() => cfg,
+            _ => return Ok(()),
+        };
+
+        let mut output = Output::new(&cfg.out_dir, cfg.rotation.clone(), cfg.csv.clone())?;
+        let mut saved = 0;
+        loop {
+            let samples = self.storage.query_page(&Filter::default(), saved, SAVE_CHUNK)?;
+            for s in &samples {
+                output.write(s)?;
+            }
+            saved += samples.len();
+            if samples.len() < SAVE_CHUNK {
+                break;
+            }
+        }
+        output.close()
+    }
+}
+
+/// Per-receiver ingestion statistics
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceStats {
+    pub frames: usize,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl DeviceStats {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            frames: 0,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    /// Average frame rate since the first frame, frames per second
+    pub fn rate(&self) -> f64 {
+        let secs = (self.last_seen - self.first_seen).num_milliseconds() as f64 / 1000.0;
+        if secs > 0.0 {
+            (self.frames - 1) as f64 / secs
+        } else {
+            0.0
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Sample {
     pub date: DateTime<Utc>,
+    pub device: String,
     pub x: f64,
     pub y: f64,
-    pub csi: Vec<Vec<Vec<f64>>>,
+    /// Complex CSI as received, `[rx][tx][tone]`
+    pub csi: Vec<Vec<Vec<ComplexDef<isize>>>>,
+    pub status: CSIStruct,
+    /// Received 802.11 frame
+    #[serde(default)]
+    pub payload: Vec<u8>,
+    /// Name of the session the sample was recorded in
+    #[serde(default)]
+    pub session: Option<String>,
+}
+
+/// How CSI values are presented by the query endpoints
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum View {
+    Amplitude,
+    Phase,
+    Complex,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View::Amplitude
+    }
+}
+
+/// A CSI matrix in one of the `View`s
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Matrix {
+    Real(Vec<Vec<Vec<f64>>>),
+    Complex(Vec<Vec<Vec<ComplexDef<isize>>>>),
+}
+
+impl Sample {
+    /// Number of rx antennas, tx antennas and tones actually present.
+    ///
+    /// The header values win when they are set, the matrix is allocated
+    /// for the largest configuration and may be bigger.
+    pub fn dims(&self) -> (usize, usize, usize) {
+        let nr = self.csi.len();
+        let nc = self.csi.first().map_or(0, Vec::len);
+        let tones = self.csi.first()
+            .and_then(|r| r.first())
+            .map_or(0, Vec::len);
+
+        let clip = |reported: u8, present: usize| match reported as usize {
+            0 => present,
+            n => n.min(present),
+        };
+
+        (
+            clip(self.status.nr, nr),
+            clip(self.status.nc, nc),
+            clip(self.status.num_tones, tones),
+        )
+    }
+
+    /// A single tone, `None` outside of `dims()`
+    pub fn get(&self, rx: usize, tx: usize, tone: usize) -> Option<ComplexDef<isize>> {
+        let (nr, nc, tones) = self.dims();
+        if rx < nr && tx < nc && tone < tones {
+            Some(self.csi[rx][tx][tone])
+        } else {
+            None
+        }
+    }
+
+    fn map(&self, f: fn(ComplexDef<isize>) -> f64) -> Vec<Vec<Vec<f64>>> {
+        let (nr, nc, tones) = self.dims();
+        self.csi[..nr].iter().map(
+            |a| a[..nc].iter().map(
+                |b| b[..tones].iter().map(|x| f(*x)).collect()
+            ).collect()
+        ).collect()
+    }
+
+    pub fn amplitude(&self) -> Vec<Vec<Vec<f64>>> {
+        self.map(abs)
+    }
+
+    /// Phase in radians
+    pub fn phase(&self) -> Vec<Vec<Vec<f64>>> {
+        self.map(phase)
+    }
+
+    pub fn view(&self, view: View) -> Matrix {
+        match view {
+            View::Amplitude => Matrix::Real(self.amplitude()),
+            View::Phase => Matrix::Real(self.phase()),
+            View::Complex => {
+                let (nr, nc, tones) = self.dims();
+                Matrix::Complex(
+                    self.csi[..nr].iter().map(
+                        |a| a[..nc].iter().map(|b| b[..tones].to_vec()).collect()
+                    ).collect()
+                )
+            }
+        }
+    }
 }